@@ -1,20 +1,212 @@
-use std::io::{Error, ErrorKind};
+use crate::store::types::{Key, NodeHash, KEY_LENGTH};
+use std::fmt;
+use std::io::Error;
+
+pub type Result<T> = std::result::Result<T, TrieError>;
+
+#[derive(Debug)]
 pub enum TrieError {
-    DuplicateLeaf,
-    InvalidChild,
-    InvalidParent,
-    InvalidBranch,
-    MissingNode,
+    // a leaf with the same key is already in the trie
+    DuplicateLeaf {
+        key: Key,
+    },
+    // there is no leaf with this key in the trie
+    LeafNotFound {
+        key: Key,
+    },
+    // a root was found where a branch or leaf was expected
+    InvalidChild {
+        depth: usize,
+    },
+    // a leaf was found where a root or branch was expected
+    InvalidParent {
+        depth: usize,
+    },
+    // a branch is missing a child
+    InvalidBranch {
+        hash: Option<NodeHash>,
+        depth: usize,
+    },
+    // a node that is referenced by its parent is not in the database
+    MissingNode {
+        hash: NodeHash,
+        depth: usize,
+    },
+    UnhashedNode,
+    InvalidKeyLength {
+        len: usize,
+    },
+    UnexpectedNode {
+        expected: &'static str,
+    },
+    // a prefix is longer than a key or than the bytes that hold it
+    InvalidPrefix {
+        bits: usize,
+    },
+    // the start of a range is not smaller than its end
+    InvalidRange {
+        start: Key,
+        end: Key,
+    },
+    InvalidProof(ProofError),
+    Database(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for TrieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrieError::DuplicateLeaf { key } => {
+                write!(f, "Leaf already exists at key {}", to_hex(key))
+            }
+            TrieError::LeafNotFound { key } => {
+                write!(f, "Leaf does not exist at key {}", to_hex(key))
+            }
+            TrieError::InvalidChild { depth } => {
+                write!(f, "Unexpected Root as child at depth {}", depth)
+            }
+            TrieError::InvalidParent { depth } => {
+                write!(f, "Unexpected Leaf as parent at depth {}", depth)
+            }
+            TrieError::InvalidBranch { hash, depth } => write!(
+                f,
+                "Invalid Branch {} at depth {}, a branch must have 2 children",
+                hash.as_deref().map(to_hex).unwrap_or_default(),
+                depth
+            ),
+            TrieError::MissingNode { hash, depth } => write!(
+                f,
+                "Node {} at depth {} is missing from the database",
+                to_hex(hash),
+                depth
+            ),
+            TrieError::UnhashedNode => {
+                write!(
+                    f,
+                    "Must compute hash before storing a node, try calling .hash()"
+                )
+            }
+            TrieError::InvalidKeyLength { len } => {
+                write!(f, "Invalid key length {}, expected {}", len, KEY_LENGTH)
+            }
+            TrieError::UnexpectedNode { expected } => {
+                write!(f, "Failed to unwrap as {}", expected)
+            }
+            TrieError::InvalidPrefix { bits } => {
+                write!(f, "Invalid prefix of {} bits", bits)
+            }
+            TrieError::InvalidRange { start, end } => write!(
+                f,
+                "Invalid range, start {} is not smaller than end {}",
+                to_hex(start),
+                to_hex(end)
+            ),
+            TrieError::InvalidProof(e) => write!(f, "Invalid proof: {}", e),
+            TrieError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TrieError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrieError::InvalidProof(e) => Some(e),
+            TrieError::Database(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProofError> for TrieError {
+    fn from(e: ProofError) -> Self {
+        TrieError::InvalidProof(e)
+    }
+}
+
+impl From<rusqlite::Error> for TrieError {
+    fn from(e: rusqlite::Error) -> Self {
+        TrieError::Database(Box::new(e))
+    }
+}
+
+impl From<bincode::Error> for TrieError {
+    fn from(e: bincode::Error) -> Self {
+        TrieError::Database(e)
+    }
 }
 
 impl From<TrieError> for Error {
     fn from(e: TrieError) -> Self {
-        match e {
-            TrieError::DuplicateLeaf => Error::new(ErrorKind::Other, "DuplicateLeaf"),
-            TrieError::InvalidChild => Error::new(ErrorKind::Other, "InvalidChild"),
-            TrieError::InvalidParent => Error::new(ErrorKind::Other, "InvalidParent"),
-            TrieError::InvalidBranch => Error::new(ErrorKind::Other, "InvalidBranch"),
-            TrieError::MissingNode => Error::new(ErrorKind::Other, "MissingNode"),
+        Error::other(e)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Reasons for rejecting a malformed proof, a well-formed proof that does not
+// match the expected root hash is not an error and verifies to false instead
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProofError {
+    EmptyProof,
+    MissingRoot,
+    MissingLeaf,
+    UnexpectedRoot { depth: usize },
+    UnexpectedLeaf { depth: usize },
+    InvalidBranch { depth: usize },
+    InvalidKeyLength { len: usize },
+    PathMismatch { depth: usize },
+    MissingNode { depth: usize },
+    LengthMismatch,
+    ConflictingLeaf,
+    NonEmptyRoot,
+    UnreachableNode,
+    InvalidRange,
+    IncompleteRange,
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::EmptyProof => write!(f, "Proof is empty"),
+            ProofError::MissingRoot => write!(f, "Proof does not start with a Root"),
+            ProofError::MissingLeaf => write!(f, "Proof does not end in a Leaf"),
+            ProofError::UnexpectedRoot { depth } => {
+                write!(f, "Unexpected Root at depth {} of the proof", depth)
+            }
+            ProofError::UnexpectedLeaf { depth } => {
+                write!(f, "Unexpected Leaf at depth {} of the proof", depth)
+            }
+            ProofError::InvalidBranch { depth } => {
+                write!(f, "Invalid Branch at depth {} of the proof", depth)
+            }
+            ProofError::InvalidKeyLength { len } => {
+                write!(f, "Invalid key length {} in proof", len)
+            }
+            ProofError::PathMismatch { depth } => write!(
+                f,
+                "Proof does not follow the path of the key at depth {}",
+                depth
+            ),
+            ProofError::MissingNode { depth } => {
+                write!(f, "Node at depth {} is missing from the proof", depth)
+            }
+            ProofError::LengthMismatch => {
+                write!(f, "Proof fields have inconsistent lengths")
+            }
+            ProofError::ConflictingLeaf => {
+                write!(f, "Conflicting Leaf does not match the path of the proof")
+            }
+            ProofError::NonEmptyRoot => write!(f, "Root is not empty on the side of the key"),
+            ProofError::UnreachableNode => {
+                write!(f, "Proof contains a node that no path of the proof reaches")
+            }
+            ProofError::InvalidRange => write!(f, "Range start is not smaller than its end"),
+            ProofError::IncompleteRange => {
+                write!(f, "Proof omits a subtree that may hold keys in the range")
+            }
         }
     }
 }
+
+impl std::error::Error for ProofError {}
@@ -0,0 +1,536 @@
+use crate::{
+    check_key,
+    error::{Result, TrieError},
+    find_key_idx_not_eq, load_node,
+    store::{
+        db::Database,
+        types::{key_bit, Key, Leaf, Node, NodeHash, Root, KEY_BITS, KEY_LENGTH},
+    },
+};
+
+// Yields the leaves below a root in ascending key order. Keys are read MSB first, so the
+// left child of every node holds the smaller keys and a depth first walk that visits left
+// before right is sorted. Nodes are only loaded when the walk reaches them.
+pub struct TrieIter<'a> {
+    db: &'a mut dyn Database,
+    root: Root,
+    // the subtrees that the iteration starts with
+    first: Vec<(NodeHash, usize)>,
+    // the smallest key with the prefix and the number of prefix bits
+    prefix: Option<(Key, usize)>,
+    // subtrees that are still to be visited, the next one is on top
+    pending: Vec<(NodeHash, usize)>,
+}
+
+pub fn iter(db: &mut dyn Database, root_node: Node) -> Result<TrieIter<'_>> {
+    let root: Root = root_node.unwrap_as_root()?;
+    let first: Vec<(NodeHash, usize)> = [&root.right, &root.left]
+        .into_iter()
+        .flatten()
+        .map(|child| (child.clone(), 1))
+        .collect();
+    Ok(TrieIter {
+        db,
+        root,
+        pending: first.clone(),
+        first,
+        prefix: None,
+    })
+}
+
+// the leaves whose keys start with the first prefix_bits bits of prefix, in ascending key
+// order. All of them are below a single subtree, the iteration starts right there.
+pub fn iter_prefix<'a>(
+    db: &'a mut dyn Database,
+    prefix: &[u8],
+    prefix_bits: usize,
+    root_node: Node,
+) -> Result<TrieIter<'a>> {
+    let root: Root = root_node.unwrap_as_root()?;
+    let first: Vec<(NodeHash, usize)> = match prefix_bits {
+        0 => [&root.right, &root.left]
+            .into_iter()
+            .flatten()
+            .map(|child| (child.clone(), 1))
+            .collect(),
+        _ => prefix_subtree(db, prefix, prefix_bits, &root)?
+            .into_iter()
+            .collect(),
+    };
+    let mut lower: Key = vec![0u8; KEY_LENGTH];
+    for idx in 0..prefix_bits {
+        lower[idx / 8] |= key_bit(prefix, idx) << (7 - idx % 8);
+    }
+    Ok(TrieIter {
+        db,
+        root,
+        pending: first.clone(),
+        first,
+        prefix: Some((lower, prefix_bits)),
+    })
+}
+
+// the hash of the node that holds exactly the keys with a prefix, it commits to all of them
+// and changes with every leaf below it. The root for an empty prefix and None if no key in
+// the trie has the prefix.
+pub fn prefix_hash(
+    db: &mut dyn Database,
+    prefix: &[u8],
+    prefix_bits: usize,
+    root_node: Node,
+) -> Result<Option<NodeHash>> {
+    let root: Root = root_node.unwrap_as_root()?;
+    if prefix_bits == 0 {
+        check_prefix(prefix, prefix_bits)?;
+        return Ok(root.hash);
+    }
+    Ok(prefix_subtree(db, prefix, prefix_bits, &root)?.map(|(node_hash, _)| node_hash))
+}
+
+// all leaves with a key in [start, end), like range_proof the range must not be empty
+pub fn range(
+    db: &mut dyn Database,
+    start: &[u8],
+    end: &[u8],
+    root_node: Node,
+) -> Result<Vec<Leaf>> {
+    check_key(start)?;
+    check_key(end)?;
+    if start >= end {
+        return Err(TrieError::InvalidRange {
+            start: start.to_vec(),
+            end: end.to_vec(),
+        });
+    }
+    let mut iter = iter(db, root_node)?;
+    iter.seek(start)?;
+    let mut leaves: Vec<Leaf> = Vec::new();
+    for leaf in iter {
+        let leaf = leaf?;
+        if leaf.key.as_slice() >= end {
+            break;
+        }
+        leaves.push(leaf);
+    }
+    Ok(leaves)
+}
+
+impl TrieIter<'_> {
+    // start over at the smallest key
+    pub fn rewind(&mut self) {
+        self.pending = self.first.clone();
+    }
+    // continue at the first leaf whose key is not smaller than start_key
+    pub fn seek(&mut self, start_key: &[u8]) -> Result<()> {
+        check_key(start_key)?;
+        if let Some((lower, _)) = &self.prefix {
+            if self.first.is_empty() || start_key <= lower.as_slice() {
+                self.rewind();
+                return Ok(());
+            }
+        }
+        self.pending = seek_path(self.db, &self.root, start_key)?.greater;
+        Ok(())
+    }
+    fn next_leaf(&mut self) -> Result<Option<Leaf>> {
+        while let Some((node_hash, depth)) = self.pending.pop() {
+            match load_node(self.db, &node_hash, depth)? {
+                Node::Branch(branch) => match (branch.left, branch.right) {
+                    (Some(left), Some(right)) => {
+                        self.pending.push((right, depth + 1));
+                        self.pending.push((left, depth + 1));
+                    }
+                    _ => {
+                        return Err(TrieError::InvalidBranch {
+                            hash: branch.hash,
+                            depth,
+                        })
+                    }
+                },
+                Node::Leaf(leaf) => {
+                    // keys after the prefix follow once a seek left its subtree
+                    if let Some((lower, prefix_bits)) = &self.prefix {
+                        if !has_prefix(&leaf.key, lower, *prefix_bits) {
+                            self.pending.clear();
+                            return Ok(None);
+                        }
+                    }
+                    return Ok(Some(leaf));
+                }
+                Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for TrieIter<'_> {
+    type Item = Result<Leaf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_leaf() {
+            Ok(leaf) => leaf.map(Ok),
+            Err(e) => {
+                // a broken subtree ends the iteration
+                self.pending.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn check_prefix(prefix: &[u8], prefix_bits: usize) -> Result<()> {
+    if prefix_bits > KEY_BITS || prefix_bits > prefix.len() * 8 {
+        return Err(TrieError::InvalidPrefix { bits: prefix_bits });
+    }
+    Ok(())
+}
+
+fn has_prefix(key: &[u8], prefix: &[u8], prefix_bits: usize) -> bool {
+    (0..prefix_bits).all(|idx| key_bit(key, idx) == key_bit(prefix, idx))
+}
+
+// the highest node on the path of a non-empty prefix whose split index lies past the
+// prefix, all keys below it share the prefix if any of them does
+fn prefix_subtree(
+    db: &mut dyn Database,
+    prefix: &[u8],
+    prefix_bits: usize,
+    root: &Root,
+) -> Result<Option<(NodeHash, usize)>> {
+    check_prefix(prefix, prefix_bits)?;
+    let root_child = if key_bit(prefix, 0) == 0 {
+        &root.left
+    } else {
+        &root.right
+    };
+    let Some(mut node_hash) = root_child.clone() else {
+        return Ok(None);
+    };
+    let mut depth: usize = 1;
+    let subtree_hash: NodeHash = loop {
+        match load_node(db, &node_hash, depth)? {
+            Node::Branch(branch) => {
+                let neq_idx = branch.split_idx() as usize;
+                if neq_idx >= prefix_bits {
+                    break node_hash;
+                }
+                let child = if key_bit(prefix, neq_idx) == 0 {
+                    branch.left
+                } else {
+                    branch.right
+                };
+                node_hash = child.ok_or(TrieError::InvalidBranch {
+                    hash: branch.hash,
+                    depth,
+                })?;
+            }
+            Node::Leaf(leaf) => {
+                if !has_prefix(&leaf.key, prefix, prefix_bits) {
+                    return Ok(None);
+                }
+                return Ok(Some((node_hash, depth)));
+            }
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+        depth += 1;
+    };
+    // the split indices on the path only covered some of the prefix bits,
+    // any leaf of the subtree shows whether the others match
+    let subtree_depth = depth;
+    let mut current_hash = subtree_hash.clone();
+    loop {
+        match load_node(db, &current_hash, depth)? {
+            Node::Branch(branch) => {
+                current_hash = branch.left.ok_or(TrieError::InvalidBranch {
+                    hash: branch.hash,
+                    depth,
+                })?;
+            }
+            Node::Leaf(leaf) => {
+                if !has_prefix(&leaf.key, prefix, prefix_bits) {
+                    return Ok(None);
+                }
+                return Ok(Some((subtree_hash, subtree_depth)));
+            }
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+        depth += 1;
+    }
+}
+
+// the leaf with the largest key that is smaller than the given key
+pub(crate) fn last_before(db: &mut dyn Database, root: &Root, key: &[u8]) -> Result<Option<Leaf>> {
+    let Some((mut node_hash, mut depth)) = seek_path(db, root, key)?.smaller.pop() else {
+        return Ok(None);
+    };
+    // the largest key of a subtree is its rightmost leaf
+    loop {
+        match load_node(db, &node_hash, depth)? {
+            Node::Branch(branch) => match branch.right {
+                Some(right) => node_hash = right,
+                None => {
+                    return Err(TrieError::InvalidBranch {
+                        hash: branch.hash,
+                        depth,
+                    })
+                }
+            },
+            Node::Leaf(leaf) => return Ok(Some(leaf)),
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+        depth += 1;
+    }
+}
+
+// the subtrees that hold all keys greater than or equal to a key and all keys smaller than
+// it, both with the subtree that is closest to the key on top
+struct SeekPath {
+    greater: Vec<(NodeHash, usize)>,
+    smaller: Vec<(NodeHash, usize)>,
+}
+
+fn seek_path(db: &mut dyn Database, root: &Root, key: &[u8]) -> Result<SeekPath> {
+    check_key(key)?;
+    let mut seek = SeekPath {
+        greater: Vec::new(),
+        smaller: Vec::new(),
+    };
+    let root_child = if key_bit(key, 0) == 0 {
+        seek.greater
+            .extend(root.right.clone().map(|right| (right, 1)));
+        root.left.clone()
+    } else {
+        seek.smaller.extend(root.left.clone().map(|left| (left, 1)));
+        root.right.clone()
+    };
+    let Some(mut node_hash) = root_child else {
+        return Ok(seek);
+    };
+    // every node on the path with its split index and the number of subtrees
+    // on either side that were collected before it was visited
+    let mut path: Vec<(NodeHash, usize, usize, usize)> = Vec::new();
+    let mut depth: usize = 1;
+    let leaf: Leaf = loop {
+        let (greater_len, smaller_len) = (seek.greater.len(), seek.smaller.len());
+        match load_node(db, &node_hash, depth)? {
+            Node::Branch(branch) => {
+                let neq_idx = branch.split_idx() as usize;
+                let (left, right) = match (branch.left, branch.right) {
+                    (Some(left), Some(right)) => (left, right),
+                    _ => {
+                        return Err(TrieError::InvalidBranch {
+                            hash: branch.hash,
+                            depth,
+                        })
+                    }
+                };
+                path.push((node_hash, neq_idx, greater_len, smaller_len));
+                node_hash = if key_bit(key, neq_idx) == 0 {
+                    seek.greater.push((right, depth + 1));
+                    left
+                } else {
+                    seek.smaller.push((left, depth + 1));
+                    right
+                };
+            }
+            Node::Leaf(leaf) => {
+                path.push((node_hash, KEY_BITS, greater_len, smaller_len));
+                break leaf;
+            }
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+        depth += 1;
+    };
+    // the leaf at the end of the path shares the longest prefix with the key, the
+    // highest subtree on the path that splits below the first differing bit is either
+    // entirely greater or entirely smaller than the key
+    let neq_idx: Option<usize> = find_key_idx_not_eq(&leaf.key, key);
+    let subtree_depth: usize = match neq_idx {
+        Some(neq_idx) => path
+            .iter()
+            .position(|(_, split_idx, _, _)| *split_idx > neq_idx)
+            .unwrap_or(path.len() - 1),
+        None => path.len() - 1,
+    };
+    let (subtree_hash, _, greater_len, smaller_len) = path.swap_remove(subtree_depth);
+    seek.greater.truncate(greater_len);
+    seek.smaller.truncate(smaller_len);
+    let subtree = (subtree_hash, subtree_depth + 1);
+    match neq_idx {
+        Some(neq_idx) if key_bit(key, neq_idx) == 1 => seek.smaller.push(subtree),
+        _ => seek.greater.push(subtree),
+    }
+    Ok(seek)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::TrieError;
+    use crate::insert_batch;
+    use crate::merkle::tests::{generate_random_key, generate_random_leaves};
+    use crate::store::db::memory::InMemoryDB;
+    use crate::store::types::{Hashable, Leaf, Node, Root};
+    use crate::trie::Trie;
+
+    #[test]
+    fn test_iter() {
+        use super::iter;
+
+        let mut db = InMemoryDB::new();
+        let empty_root = Node::Root(Root::empty());
+        assert!(iter(&mut db, empty_root.clone()).unwrap().next().is_none());
+        let mut empty_iter = iter(&mut db, empty_root.clone()).unwrap();
+        empty_iter.seek(&[0u8; 32]).unwrap();
+        assert!(empty_iter.next().is_none());
+
+        let mut leaves: Vec<Leaf> = generate_random_leaves(200);
+        // keys that only differ in their last bit
+        let mut twin_key = leaves[0].key.clone();
+        twin_key[31] ^= 1;
+        let mut twin = Leaf::new(twin_key, Some(vec![1]));
+        twin.hash();
+        leaves.push(twin);
+        let root = Node::Root(insert_batch(&mut db, leaves.clone(), empty_root).unwrap());
+        let mut keys: Vec<Vec<u8>> = leaves.iter().map(|leaf| leaf.key.clone()).collect();
+        keys.sort();
+
+        let iterated: Vec<Leaf> = iter(&mut db, root.clone())
+            .unwrap()
+            .collect::<crate::error::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            iterated
+                .iter()
+                .map(|leaf| leaf.key.clone())
+                .collect::<Vec<_>>(),
+            keys
+        );
+        assert!(iterated.iter().all(|leaf| leaf.data.is_some()));
+
+        let mut start_keys: Vec<Vec<u8>> = (0..50).map(|_| generate_random_key()).collect();
+        start_keys.extend([vec![0u8; 32], vec![0xff; 32], keys[0].clone()]);
+        start_keys.extend(keys.iter().step_by(10).cloned());
+        // the successor of an existing key
+        let mut successor = keys[100].clone();
+        successor[31] = successor[31].wrapping_add(1);
+        start_keys.push(successor);
+        let mut trie_iter = iter(&mut db, root.clone()).unwrap();
+        for start_key in start_keys {
+            trie_iter.seek(&start_key).unwrap();
+            let seeked: Vec<Vec<u8>> = trie_iter.by_ref().map(|leaf| leaf.unwrap().key).collect();
+            let expected: Vec<Vec<u8>> = keys
+                .iter()
+                .filter(|key| **key >= start_key)
+                .cloned()
+                .collect();
+            assert_eq!(seeked, expected);
+        }
+        trie_iter.rewind();
+        assert_eq!(trie_iter.count(), keys.len());
+        assert!(matches!(
+            iter(&mut db, root).unwrap().seek(&[0u8; 31]),
+            Err(TrieError::InvalidKeyLength { len: 31 })
+        ));
+
+        // the trie handle iterates its current root
+        let mut trie = Trie::new(InMemoryDB::new()).unwrap();
+        for key in keys.iter().rev().take(10) {
+            trie.insert(key.clone(), vec![0]).unwrap();
+        }
+        let iterated: Vec<Vec<u8>> = trie.iter().unwrap().map(|leaf| leaf.unwrap().key).collect();
+        assert_eq!(iterated, keys[keys.len() - 10..].to_vec());
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        use crate::store::types::key_bit;
+
+        let mut trie = Trie::new(InMemoryDB::new()).unwrap();
+        // three namespaces in the first byte, 0x80 and 0x81 share their first 7 bits
+        let namespaces: [u8; 3] = [0x00, 0x80, 0x81];
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for (idx, namespace) in namespaces.iter().enumerate() {
+            for _ in 0..(10 * idx + 1) {
+                let mut key = generate_random_key();
+                key[0] = *namespace;
+                trie.insert(key.clone(), vec![*namespace]).unwrap();
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        let prefixed = |prefix: &[u8], prefix_bits: usize| -> Vec<Vec<u8>> {
+            keys.iter()
+                .filter(|key| (0..prefix_bits).all(|idx| key_bit(key, idx) == key_bit(prefix, idx)))
+                .cloned()
+                .collect()
+        };
+        let mut prefixes: Vec<(Vec<u8>, usize)> = vec![
+            (vec![], 0),
+            (vec![0x00], 8),
+            (vec![0x80], 7),
+            (vec![0x81], 8),
+            (vec![0x80], 1),
+            (vec![0x40], 2),
+            (vec![0x82], 8),
+            (keys[5].clone(), 256),
+        ];
+        // prefixes that are longer than the namespace
+        prefixes.extend(keys.iter().step_by(4).map(|key| (key[..2].to_vec(), 12)));
+        for (prefix, prefix_bits) in prefixes {
+            let iterated: Vec<Vec<u8>> = trie
+                .iter_prefix(&prefix, prefix_bits)
+                .unwrap()
+                .map(|leaf| leaf.unwrap().key)
+                .collect();
+            let expected = prefixed(&prefix, prefix_bits);
+            assert_eq!(iterated, expected);
+            assert_eq!(
+                trie.prefix_hash(&prefix, prefix_bits).unwrap().is_some(),
+                !expected.is_empty()
+            );
+
+            // seeking stays within the prefix
+            let mut prefix_iter = trie.iter_prefix(&prefix, prefix_bits).unwrap();
+            for start_key in [
+                vec![0u8; 32],
+                keys[3].clone(),
+                keys[20].clone(),
+                vec![0xff; 32],
+            ] {
+                prefix_iter.seek(&start_key).unwrap();
+                let seeked: Vec<Vec<u8>> =
+                    prefix_iter.by_ref().map(|leaf| leaf.unwrap().key).collect();
+                let expected: Vec<Vec<u8>> = expected
+                    .iter()
+                    .filter(|key| **key >= start_key)
+                    .cloned()
+                    .collect();
+                assert_eq!(seeked, expected);
+            }
+        }
+        assert_eq!(
+            trie.prefix_hash(&[], 0).unwrap().as_ref(),
+            Some(trie.root_hash())
+        );
+        // a namespace with a single key is committed to by its leaf
+        let leaf = trie.get(&keys[0]).unwrap().unwrap();
+        assert_eq!(trie.prefix_hash(&[0x00], 8).unwrap(), leaf.hash);
+
+        // the hash of a namespace only changes with its own leaves
+        let namespace_hash = trie.prefix_hash(&[0x81], 8).unwrap();
+        let mut key = generate_random_key();
+        key[0] = 0x80;
+        trie.insert(key, vec![0x80]).unwrap();
+        assert_eq!(trie.prefix_hash(&[0x81], 8).unwrap(), namespace_hash);
+        let mut key = generate_random_key();
+        key[0] = 0x81;
+        trie.insert(key, vec![0x81]).unwrap();
+        assert_ne!(trie.prefix_hash(&[0x81], 8).unwrap(), namespace_hash);
+
+        assert!(matches!(
+            trie.iter_prefix(&[0x00], 9),
+            Err(TrieError::InvalidPrefix { bits: 9 })
+        ));
+    }
+}
@@ -1,293 +1,691 @@
+use error::{Result, TrieError};
 use store::{
-    db::Database,
-    types::{Branch, Key, Leaf, Node, Root},
+    db::{Database, WriteBatch},
+    hasher::{Hasher, Sha256Hasher},
+    types::{key_bit, Branch, Key, Leaf, Node, NodeHash, Root, KEY_BITS, KEY_LENGTH},
 };
 
 pub mod error;
+pub mod iter;
 pub mod merkle;
 pub mod store;
-use anyhow::{bail, Result};
+pub mod trie;
 
-pub fn check_leaf(db: &mut dyn Database, leaf_expected: &Leaf, mut current_node: Node) -> bool {
-    #[allow(unused_assignments)]
-    let mut result: bool = false;
+pub fn check_leaf(
+    db: &mut dyn Database,
+    leaf_expected: &Leaf,
+    mut current_node: Node,
+) -> Result<bool> {
+    check_key(&leaf_expected.key)?;
+    let mut depth: usize = 0;
     loop {
         match &current_node {
             Node::Branch(branch) => {
-                let branch_prefix = &branch.key;
-                let neq_idx = &branch_prefix[0];
-                let child_idx = leaf_expected.key[*neq_idx as usize];
-                if child_idx == 0 {
-                    current_node = db.get(&branch.left.clone().unwrap()).unwrap().clone();
-                } else {
-                    current_node = db.get(&branch.right.clone().unwrap()).unwrap().clone();
-                }
+                let neq_idx = branch.split_idx() as usize;
+                let child_idx = key_bit(&leaf_expected.key, neq_idx);
+                current_node = load_child(db, branch, child_idx, depth)?;
             }
-            Node::Leaf(leaf) => {
-                if leaf.hash == leaf_expected.hash {
-                    result = true;
+            Node::Leaf(leaf) => return Ok(leaf.hash == leaf_expected.hash),
+            Node::Root(root) => {
+                let child = if key_bit(&leaf_expected.key, 0) == 0 {
+                    &root.left
                 } else {
-                    result = false;
+                    &root.right
+                };
+                match child {
+                    Some(node_hash) => current_node = load_node(db, node_hash, depth + 1)?,
+                    None => return Ok(false),
                 }
-                break;
             }
-            Node::Root(root) => {
-                if leaf_expected.key[0] == 0 {
-                    match &root.left {
-                        Some(node) => {
-                            current_node = db.get(&node).unwrap().clone();
-                        }
-                        None => {
-                            result = false;
-                            break;
-                        }
-                    }
-                } else {
-                    match &root.right {
-                        Some(node) => {
-                            current_node = db.get(&node).unwrap().clone();
-                        }
-                        None => {
-                            result = false;
-                            break;
-                        }
-                    }
+        }
+        depth += 1;
+    }
+}
+
+// read the leaf stored at a key, None if the key is not in the trie
+pub fn get_leaf(db: &mut dyn Database, key: &[u8], root_node: Node) -> Result<Option<Leaf>> {
+    check_key(key)?;
+    let root: Root = root_node.unwrap_as_root()?;
+    let mut current_node = match if key_bit(key, 0) == 0 {
+        root.left
+    } else {
+        root.right
+    } {
+        Some(node_hash) => load_node(db, &node_hash, 1)?,
+        None => return Ok(None),
+    };
+    let mut depth: usize = 1;
+    loop {
+        match current_node {
+            Node::Branch(branch) => {
+                let child_idx = key_bit(key, branch.split_idx() as usize);
+                current_node = load_child(db, &branch, child_idx, depth)?;
+            }
+            Node::Leaf(leaf) => {
+                if leaf.key == key {
+                    return Ok(Some(leaf));
                 }
+                return Ok(None);
             }
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
         }
+        depth += 1;
     }
-    result
 }
 
 pub fn insert_leaf(db: &mut dyn Database, new_leaf: &mut Leaf, root_node: Node) -> Result<Root> {
-    assert_eq!(new_leaf.key.len(), 256);
-    // don't insert if a leaf already exists at the given key
-    if check_leaf(db, &new_leaf, root_node.clone()) {
-        bail!("Leaf already exists!");
-    }
-    let modified_nodes = traverse_trie(db, new_leaf, root_node.clone(), false)?;
-    let mut new_root = update_modified_leafs(db, modified_nodes, root_node.unwrap_as_root()?)?;
-    new_root.hash_and_store(db);
+    insert_leaf_with(db, new_leaf, root_node, &Sha256Hasher)
+}
+
+// the *_with functions hash new nodes with the given hasher instead of SHA-256,
+// the leaf must already be hashed with the same hasher
+pub fn insert_leaf_with(
+    db: &mut dyn Database,
+    new_leaf: &mut Leaf,
+    root_node: Node,
+    hasher: &dyn Hasher,
+) -> Result<Root> {
+    // fails if a leaf already exists at the given key
+    let (new_root, _) = write_leaf(db, new_leaf, root_node, TraverseMode::Insert, hasher)?;
     Ok(new_root)
 }
 
+pub fn update_leaf(db: &mut dyn Database, new_leaf: &mut Leaf, root_node: Node) -> Result<Root> {
+    update_leaf_with(db, new_leaf, root_node, &Sha256Hasher)
+}
+
+pub fn update_leaf_with(
+    db: &mut dyn Database,
+    new_leaf: &mut Leaf,
+    root_node: Node,
+    hasher: &dyn Hasher,
+) -> Result<Root> {
+    let (new_root, _) = write_leaf(db, new_leaf, root_node, TraverseMode::Update, hasher)?;
+    Ok(new_root)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpsertKind {
+    Inserted,
+    Updated,
+}
+
+// insert a new leaf or replace the data of an existing one in a single traversal
+pub fn upsert_leaf(
+    db: &mut dyn Database,
+    new_leaf: &mut Leaf,
+    root_node: Node,
+) -> Result<(Root, UpsertKind)> {
+    upsert_leaf_with(db, new_leaf, root_node, &Sha256Hasher)
+}
+
+pub fn upsert_leaf_with(
+    db: &mut dyn Database,
+    new_leaf: &mut Leaf,
+    root_node: Node,
+    hasher: &dyn Hasher,
+) -> Result<(Root, UpsertKind)> {
+    write_leaf(db, new_leaf, root_node, TraverseMode::Upsert, hasher)
+}
+
+fn write_leaf(
+    db: &mut dyn Database,
+    new_leaf: &mut Leaf,
+    root_node: Node,
+    mode: TraverseMode,
+    hasher: &dyn Hasher,
+) -> Result<(Root, UpsertKind)> {
+    check_key(&new_leaf.key)?;
+    commit_atomically(db, |db| {
+        let (modified_nodes, kind) = traverse_trie(db, new_leaf, root_node.clone(), mode, hasher)?;
+        let mut new_root =
+            update_modified_leafs(db, modified_nodes, root_node.unwrap_as_root()?, hasher)?;
+        new_root.hash_and_store_with(db, hasher)?;
+        Ok((new_root, kind))
+    })
+}
+
+// run a mutation against a write batch, either all nodes that it stores
+// are committed to the database or none of them
+fn commit_atomically<T>(
+    db: &mut dyn Database,
+    mutation: impl FnOnce(&mut dyn Database) -> Result<T>,
+) -> Result<T> {
+    let mut batch = WriteBatch::new(db);
+    let result = mutation(&mut batch)?;
+    batch.commit()?;
+    Ok(result)
+}
+
+pub fn remove_leaf(db: &mut dyn Database, key: &[u8], root_node: Node) -> Result<Root> {
+    remove_leaf_with(db, key, root_node, &Sha256Hasher)
+}
+
+pub fn remove_leaf_with(
+    db: &mut dyn Database,
+    key: &[u8],
+    root_node: Node,
+    hasher: &dyn Hasher,
+) -> Result<Root> {
+    check_key(key)?;
+    commit_atomically(db, |db| remove_from_trie(db, key, root_node, hasher))
+}
+
+fn remove_from_trie(
+    db: &mut dyn Database,
+    key: &[u8],
+    root_node: Node,
+    hasher: &dyn Hasher,
+) -> Result<Root> {
+    let mut root: Root = root_node.unwrap_as_root()?;
+    let mut modified_nodes: Vec<(u8, Node)> = Vec::new();
+    let root_child = if key_bit(key, 0) == 0 {
+        root.left.clone()
+    } else {
+        root.right.clone()
+    };
+    let mut current_node: Node = match root_child {
+        Some(node_hash) => load_node(db, &node_hash, 1)?,
+        None => return Err(TrieError::LeafNotFound { key: key.to_vec() }),
+    };
+    let mut current_node_pos: u8 = key_bit(key, 0);
+    let mut depth: usize = 1;
+    loop {
+        match current_node {
+            Node::Branch(branch) => {
+                let child_pos: u8 = key_bit(key, branch.split_idx() as usize);
+                current_node = load_child(db, &branch, child_pos, depth)?;
+                modified_nodes.push((current_node_pos, Node::Branch(branch)));
+                current_node_pos = child_pos;
+            }
+            Node::Leaf(leaf) => {
+                if leaf.key != key {
+                    return Err(TrieError::LeafNotFound { key: key.to_vec() });
+                }
+                break;
+            }
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+        depth += 1;
+    }
+    match modified_nodes.pop() {
+        // the leaf was a direct child of the root, that side becomes empty
+        None => {
+            if current_node_pos == 0 {
+                root.left = None;
+            } else {
+                root.right = None;
+            }
+            root.hash_and_store_with(db, hasher)?;
+            Ok(root)
+        }
+        // collapse the parent branch into the sibling of the removed leaf
+        Some((parent_pos, parent)) => {
+            let parent: Branch = parent.unwrap_as_branch()?;
+            let sibling_pos: u8 = 1 - current_node_pos;
+            let sibling: Node = load_child(db, &parent, sibling_pos, depth - 1)?;
+            modified_nodes.push((parent_pos, sibling));
+            let mut new_root = update_modified_leafs(db, modified_nodes, root, hasher)?;
+            new_root.hash_and_store_with(db, hasher)?;
+            Ok(new_root)
+        }
+    }
+}
+
+// insert many leafs at once, every touched node is hashed and stored exactly once
+// and a single new root is created for the whole batch
+pub fn insert_batch(db: &mut dyn Database, leaves: Vec<Leaf>, root_node: Node) -> Result<Root> {
+    insert_batch_with(db, leaves, root_node, &Sha256Hasher)
+}
+
+pub fn insert_batch_with(
+    db: &mut dyn Database,
+    leaves: Vec<Leaf>,
+    root_node: Node,
+    hasher: &dyn Hasher,
+) -> Result<Root> {
+    commit_atomically(db, |db| {
+        insert_batch_into_trie(db, leaves, root_node, hasher)
+    })
+}
+
+fn insert_batch_into_trie(
+    db: &mut dyn Database,
+    mut leaves: Vec<Leaf>,
+    root_node: Node,
+    hasher: &dyn Hasher,
+) -> Result<Root> {
+    let mut root: Root = root_node.unwrap_as_root()?;
+    for leaf in &leaves {
+        check_key(&leaf.key)?;
+        if leaf.hash.is_none() {
+            return Err(TrieError::UnhashedNode);
+        }
+    }
+    if leaves.is_empty() {
+        return Ok(root);
+    }
+    leaves.sort_by(|a, b| a.key.cmp(&b.key));
+    if let Some(pair) = leaves.windows(2).find(|pair| pair[0].key == pair[1].key) {
+        return Err(TrieError::DuplicateLeaf {
+            key: pair[0].key.clone(),
+        });
+    }
+    let split = leaves.partition_point(|leaf| key_bit(&leaf.key, 0) == 0);
+    let (left_leaves, right_leaves) = leaves.split_at(split);
+    if !left_leaves.is_empty() {
+        let left = insert_into_child(db, root.left.clone(), left_leaves, hasher)?;
+        root.left = Some(node_hash(&left)?);
+    }
+    if !right_leaves.is_empty() {
+        let right = insert_into_child(db, root.right.clone(), right_leaves, hasher)?;
+        root.right = Some(node_hash(&right)?);
+    }
+    root.hash_and_store_with(db, hasher)?;
+    Ok(root)
+}
+
+fn insert_into_child(
+    db: &mut dyn Database,
+    child: Option<NodeHash>,
+    leaves: &[Leaf],
+    hasher: &dyn Hasher,
+) -> Result<Node> {
+    match child {
+        Some(child_hash) => {
+            let child_node: Node = load_node(db, &child_hash, 1)?;
+            let representative = leftmost_leaf_key(db, child_node.clone(), 1)?;
+            insert_into_subtree(db, child_node, &representative, leaves, 1, hasher)
+        }
+        None => build_subtree(db, leaves, hasher),
+    }
+}
+
+// insert sorted leaves into an existing subtree, the representative is the key
+// of any leaf below the subtree and is used to detect where the new keys diverge
+fn insert_into_subtree(
+    db: &mut dyn Database,
+    subtree: Node,
+    representative: &Key,
+    leaves: &[Leaf],
+    depth: usize,
+    hasher: &dyn Hasher,
+) -> Result<Node> {
+    if leaves.is_empty() {
+        return Ok(subtree);
+    }
+    let split_idx: usize = match &subtree {
+        Node::Branch(branch) => branch.split_idx() as usize,
+        Node::Leaf(_) => KEY_BITS,
+        Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+    };
+    let neq_idx: Option<usize> = leaves
+        .iter()
+        .filter_map(|leaf| find_key_idx_not_eq(&leaf.key, representative))
+        .filter(|idx| *idx < split_idx)
+        .min();
+    match (neq_idx, subtree) {
+        // some keys leave the subtree above its split index, a new branch
+        // is created with the existing subtree on one side
+        (Some(neq_idx), subtree) => {
+            let subtree_pos: u8 = key_bit(representative, neq_idx);
+            let split = leaves.partition_point(|leaf| key_bit(&leaf.key, neq_idx) == 0);
+            let (left_leaves, right_leaves) = leaves.split_at(split);
+            let (same_side, other_side) = if subtree_pos == 0 {
+                (left_leaves, right_leaves)
+            } else {
+                (right_leaves, left_leaves)
+            };
+            let subtree =
+                insert_into_subtree(db, subtree, representative, same_side, depth + 1, hasher)?;
+            let other = build_subtree(db, other_side, hasher)?;
+            let mut new_branch: Branch = Branch::empty(neq_idx as u8);
+            if subtree_pos == 0 {
+                new_branch.update(Some(node_hash(&subtree)?), Some(node_hash(&other)?));
+            } else {
+                new_branch.update(Some(node_hash(&other)?), Some(node_hash(&subtree)?));
+            }
+            new_branch.hash_and_store_with(db, hasher)?;
+            Ok(Node::Branch(new_branch))
+        }
+        (None, Node::Leaf(_)) => Err(TrieError::DuplicateLeaf {
+            key: representative.clone(),
+        }),
+        (None, Node::Branch(mut branch)) => {
+            let split = leaves.partition_point(|leaf| key_bit(&leaf.key, split_idx) == 0);
+            let (left_leaves, right_leaves) = leaves.split_at(split);
+            let (left_hash, right_hash) = match (branch.left.clone(), branch.right.clone()) {
+                (Some(left_hash), Some(right_hash)) => (left_hash, right_hash),
+                _ => {
+                    return Err(TrieError::InvalidBranch {
+                        hash: branch.hash,
+                        depth,
+                    })
+                }
+            };
+            // the representative is only known to be below one of the children
+            let mut descend = |child_hash: NodeHash, child_pos: u8, leaves: &[Leaf]| {
+                if leaves.is_empty() {
+                    return Ok(child_hash);
+                }
+                let child: Node = load_node(db, &child_hash, depth + 1)?;
+                let child_representative = if key_bit(representative, split_idx) == child_pos {
+                    representative.clone()
+                } else {
+                    leftmost_leaf_key(db, child.clone(), depth + 1)?
+                };
+                node_hash(&insert_into_subtree(
+                    db,
+                    child,
+                    &child_representative,
+                    leaves,
+                    depth + 1,
+                    hasher,
+                )?)
+            };
+            let left_hash = descend(left_hash, 0, left_leaves)?;
+            let right_hash = descend(right_hash, 1, right_leaves)?;
+            branch.update(Some(left_hash), Some(right_hash));
+            branch.hash_and_store_with(db, hasher)?;
+            Ok(Node::Branch(branch))
+        }
+        (None, Node::Root(_)) => Err(TrieError::InvalidChild { depth }),
+    }
+}
+
+// build a new subtree from sorted leaves with distinct keys
+fn build_subtree(db: &mut dyn Database, leaves: &[Leaf], hasher: &dyn Hasher) -> Result<Node> {
+    if leaves.len() == 1 {
+        leaves[0].store(db)?;
+        return Ok(Node::Leaf(leaves[0].clone()));
+    }
+    let first = &leaves[0].key;
+    let last = &leaves[leaves.len() - 1].key;
+    let neq_idx = match find_key_idx_not_eq(first, last) {
+        Some(neq_idx) => neq_idx,
+        None => return Err(TrieError::DuplicateLeaf { key: first.clone() }),
+    };
+    let split = leaves.partition_point(|leaf| key_bit(&leaf.key, neq_idx) == 0);
+    let left = build_subtree(db, &leaves[..split], hasher)?;
+    let right = build_subtree(db, &leaves[split..], hasher)?;
+    let mut new_branch: Branch = Branch::new(
+        neq_idx as u8,
+        Some(node_hash(&left)?),
+        Some(node_hash(&right)?),
+    );
+    new_branch.hash_and_store_with(db, hasher)?;
+    Ok(Node::Branch(new_branch))
+}
+
+fn leftmost_leaf_key(
+    db: &mut dyn Database,
+    mut current_node: Node,
+    mut depth: usize,
+) -> Result<Key> {
+    loop {
+        match current_node {
+            Node::Branch(branch) => current_node = load_child(db, &branch, 0, depth)?,
+            Node::Leaf(leaf) => return Ok(leaf.key),
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+        depth += 1;
+    }
+}
+
+pub(crate) fn node_hash(node: &Node) -> Result<NodeHash> {
+    let hash = match node {
+        Node::Root(root) => root.hash.clone(),
+        Node::Branch(branch) => branch.hash.clone(),
+        Node::Leaf(leaf) => leaf.hash.clone(),
+    };
+    hash.ok_or(TrieError::UnhashedNode)
+}
+
+// load a node that is referenced by its parent, a missing node means the database is corrupted
+pub(crate) fn load_node(db: &mut dyn Database, node_hash: &NodeHash, depth: usize) -> Result<Node> {
+    match db.get(node_hash)? {
+        Some(node) => Ok(node),
+        None => Err(TrieError::MissingNode {
+            hash: node_hash.clone(),
+            depth,
+        }),
+    }
+}
+
+// load the left (0) or right (1) child of a branch at the given depth
+pub(crate) fn load_child(
+    db: &mut dyn Database,
+    branch: &Branch,
+    child_pos: u8,
+    depth: usize,
+) -> Result<Node> {
+    let child = if child_pos == 0 {
+        &branch.left
+    } else {
+        &branch.right
+    };
+    match child {
+        Some(node_hash) => load_node(db, node_hash, depth + 1),
+        None => Err(TrieError::InvalidBranch {
+            hash: branch.hash.clone(),
+            depth,
+        }),
+    }
+}
+
+pub(crate) fn check_key(key: &[u8]) -> Result<()> {
+    if key.len() != KEY_LENGTH {
+        return Err(TrieError::InvalidKeyLength { len: key.len() });
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TraverseMode {
+    Insert,
+    Update,
+    Upsert,
+}
+
 fn traverse_trie(
     db: &mut dyn Database,
     new_leaf: &mut Leaf,
     root_node: Node,
-    update: bool,
-) -> Result<Vec<(u8, Node)>> {
+    mode: TraverseMode,
+    hasher: &dyn Hasher,
+) -> Result<(Vec<(u8, Node)>, UpsertKind)> {
+    if new_leaf.hash.is_none() {
+        return Err(TrieError::UnhashedNode);
+    }
     let mut modified_nodes: Vec<(u8, Node)> = Vec::new();
     let mut current_node: Node = root_node.clone();
     let mut current_node_pos: u8 = 0;
+    let mut depth: usize = 0;
+    let kind: UpsertKind;
     loop {
         match &mut current_node {
             Node::Root(root) => {
-                if new_leaf.key[0] == 0 {
-                    match root.left.clone() {
-                        Some(node_hash) => {
-                            current_node = db.get(&node_hash).unwrap().clone();
-                            current_node_pos = 0;
-                        }
-                        None => {
-                            let mut root: Root = current_node.unwrap_as_root()?;
-                            root.left = Some(new_leaf.hash.clone().unwrap());
-                            new_leaf.store(db);
-                            modified_nodes.push((0, Node::Leaf(new_leaf.clone())));
-                            break;
-                        }
-                    }
+                let (child, child_pos) = if key_bit(&new_leaf.key, 0) == 0 {
+                    (root.left.clone(), 0)
                 } else {
-                    match root.right.clone() {
-                        Some(node_hash) => {
-                            current_node = db.get(&node_hash).unwrap().clone();
-                            current_node_pos = 1;
-                        }
-                        None => {
-                            let mut root = current_node.clone().unwrap_as_root()?;
-                            root.right = Some(new_leaf.hash.clone().unwrap());
-                            new_leaf.store(db);
-                            modified_nodes.push((1, Node::Leaf(new_leaf.clone())));
-                            break;
+                    (root.right.clone(), 1)
+                };
+                match child {
+                    Some(node_hash) => {
+                        current_node = load_node(db, &node_hash, depth + 1)?;
+                        current_node_pos = child_pos;
+                    }
+                    None => {
+                        if mode == TraverseMode::Update {
+                            return Err(TrieError::LeafNotFound {
+                                key: new_leaf.key.clone(),
+                            });
                         }
+                        new_leaf.store(db)?;
+                        modified_nodes.push((child_pos, Node::Leaf(new_leaf.clone())));
+                        kind = UpsertKind::Inserted;
+                        break;
                     }
                 }
             }
             Node::Branch(branch) => {
-                if new_leaf.key[branch.key[0] as usize] == 0 {
-                    match branch.left.clone() {
-                        Some(node_hash) => {
-                            modified_nodes.push((current_node_pos, Node::Branch(branch.clone())));
-                            current_node = db.get(&node_hash).unwrap().clone();
-                            current_node_pos = 0;
+                let child_pos: u8 = key_bit(&new_leaf.key, branch.split_idx() as usize);
+                let child = load_child(db, branch, child_pos, depth)?;
+                modified_nodes.push((current_node_pos, Node::Branch(branch.clone())));
+                current_node = child;
+                current_node_pos = child_pos;
+            }
+            Node::Leaf(leaf) => {
+                match find_key_idx_not_eq(&new_leaf.key, &leaf.key) {
+                    Some(neq_idx) => {
+                        // the path only leads to this leaf, if the key is different
+                        // the leaf we want to update is not in the trie
+                        if mode == TraverseMode::Update {
+                            return Err(TrieError::LeafNotFound {
+                                key: new_leaf.key.clone(),
+                            });
                         }
-                        None => {
-                            bail!("A branch must have 2 children")
+                        let new_leaf_pos: u8 = key_bit(&new_leaf.key, neq_idx);
+                        // the new branch must sit above the first branch on the path that
+                        // splits at a later index, so that the shape of the trie only
+                        // depends on the keys it contains and not on the insertion order
+                        let displaced_idx = modified_nodes.iter().position(|(_, node)| {
+                            matches!(node, Node::Branch(branch) if branch.split_idx() as usize > neq_idx)
+                        });
+                        let (displaced_pos, displaced_hash) = match displaced_idx {
+                            Some(idx) => {
+                                let (pos, node) = modified_nodes[idx].clone();
+                                modified_nodes.truncate(idx);
+                                (pos, node.unwrap_as_branch()?.hash)
+                            }
+                            None => (current_node_pos, leaf.hash.clone()),
+                        };
+                        new_leaf.store(db)?;
+                        let mut new_branch: Branch = Branch::empty(neq_idx as u8);
+                        if new_leaf_pos == 0 {
+                            new_branch.left = new_leaf.hash.clone();
+                            new_branch.right = displaced_hash;
+                        } else {
+                            new_branch.left = displaced_hash;
+                            new_branch.right = new_leaf.hash.clone();
                         }
+                        new_branch.hash_and_store_with(db, hasher)?;
+                        modified_nodes.push((displaced_pos, Node::Branch(new_branch)));
+                        kind = UpsertKind::Inserted;
                     }
-                } else {
-                    match branch.right.clone() {
-                        Some(node_hash) => {
-                            modified_nodes.push((current_node_pos, Node::Branch(branch.clone())));
-                            current_node = db.get(&node_hash).unwrap().clone();
-                            current_node_pos = 1;
+                    None => {
+                        if mode == TraverseMode::Insert {
+                            return Err(TrieError::DuplicateLeaf {
+                                key: new_leaf.key.clone(),
+                            });
                         }
-                        None => {
-                            bail!("A branch must have 2 children")
-                        }
-                    }
-                }
-            }
-            Node::Leaf(leaf) => {
-                if !update {
-                    let neq_idx = find_key_idx_not_eq(&new_leaf.key, &leaf.key)
-                        .expect("Can't insert duplicate Leaf");
-                    let new_leaf_pos: u8 = new_leaf.key[neq_idx];
-                    match new_leaf.hash {
-                        Some(_) => {}
-                        None => bail!("Leaf was not hashed!"),
-                    }
-                    new_leaf.store(db);
-                    let mut new_branch: Branch = Branch::empty(vec![neq_idx as u8]);
-                    if new_leaf_pos == 0 {
-                        new_branch.left = new_leaf.hash.clone();
-                        new_branch.right = leaf.hash.clone();
-                    } else {
-                        new_branch.left = leaf.hash.clone();
-                        new_branch.right = new_leaf.hash.clone();
+                        new_leaf.store(db)?;
+                        modified_nodes.push((current_node_pos, Node::Leaf(new_leaf.clone())));
+                        kind = UpsertKind::Updated;
                     }
-                    new_branch.hash_and_store(db);
-                    modified_nodes.push((current_node_pos, Node::Branch(new_branch)));
-                    break;
-                } else {
-                    todo!("Currently not supported");
                 }
+                break;
             }
         }
+        depth += 1;
     }
-    Ok(modified_nodes)
+    Ok((modified_nodes, kind))
 }
 
 fn update_modified_leafs(
     db: &mut dyn Database,
     mut modified_nodes: Vec<(u8, Node)>,
     old_root: Root,
+    hasher: &dyn Hasher,
 ) -> Result<Root> {
     let mut new_root = Root::empty();
     modified_nodes.reverse();
     modified_nodes.push((0, Node::Root(old_root)));
+    let depth = modified_nodes.len() - 1;
     for i in 1..modified_nodes.len() {
         let child = modified_nodes[i - 1].clone();
         let parent = modified_nodes[i].clone();
+        let child_hash: NodeHash = match &child.1 {
+            Node::Root(_) => {
+                return Err(TrieError::InvalidChild {
+                    depth: depth + 1 - i,
+                })
+            }
+            child => node_hash(child)?,
+        };
         match parent.1 {
-            Node::Root(mut root) => match &child.1 {
-                Node::Branch(branch) => {
-                    if child.0 == 0 {
-                        root.left = Some(branch.clone().hash.unwrap());
-                        assert!(root.left.is_some());
-                        root.hash_and_store(db);
-                        new_root = root;
-                    } else {
-                        root.right = Some(branch.clone().hash.unwrap());
-                        assert!(root.right.is_some());
-                        root.hash_and_store(db);
-                        new_root = root;
-                    }
-                }
-                Node::Leaf(leaf) => {
-                    if child.0 == 0 {
-                        root.left = Some(leaf.clone().hash.unwrap());
-                        assert!(root.left.is_some());
-                        root.hash_and_store(db);
-                        new_root = root;
-                    } else {
-                        root.right = Some(leaf.clone().hash.unwrap());
-                        assert!(root.right.is_some());
-                        root.hash_and_store(db);
-                        new_root = root;
-                    }
-                }
-                _ => bail!("This should never happen, child is root"),
-            },
-            Node::Branch(mut branch) => match &child.1 {
-                Node::Branch(child_branch) => {
-                    if child.0 == 0 {
-                        branch.left = Some(child_branch.clone().hash.unwrap());
-                        branch.hash_and_store(db);
-                        modified_nodes[i] = (parent.0, Node::Branch(branch.clone()));
-                    } else {
-                        branch.right = Some(child_branch.clone().hash.unwrap());
-                        branch.hash_and_store(db);
-                        modified_nodes[i] = (parent.0, Node::Branch(branch.clone()));
-                    }
+            Node::Root(mut root) => {
+                if child.0 == 0 {
+                    root.left = Some(child_hash);
+                } else {
+                    root.right = Some(child_hash);
                 }
-                Node::Leaf(leaf) => {
-                    if child.0 == 0 {
-                        branch.left = Some(leaf.clone().hash.unwrap());
-                        branch.hash_and_store(db);
-                        modified_nodes[i] = (parent.0, Node::Branch(branch.clone()));
-                    } else {
-                        branch.right = Some(leaf.clone().hash.unwrap());
-                        branch.hash_and_store(db);
-                        modified_nodes[i] = (parent.0, Node::Branch(branch.clone()));
-                    }
+                root.hash_and_store_with(db, hasher)?;
+                new_root = root;
+            }
+            Node::Branch(mut branch) => {
+                if child.0 == 0 {
+                    branch.left = Some(child_hash);
+                } else {
+                    branch.right = Some(child_hash);
                 }
-                _ => bail!("This should never happen, child is leaf"),
-            },
-            Node::Leaf(_) => bail!("This should never happen, parent is leaf"),
+                branch.hash_and_store_with(db, hasher)?;
+                modified_nodes[i] = (parent.0, Node::Branch(branch.clone()));
+            }
+            Node::Leaf(_) => return Err(TrieError::InvalidParent { depth: depth - i }),
         }
     }
-    assert!(new_root.left.is_some() || new_root.right.is_some());
+    // a mutation always leaves a node below the root, a path without one never reached it
+    if new_root.left.is_none() && new_root.right.is_none() {
+        return Err(TrieError::InvalidChild { depth: 1 });
+    }
     Ok(new_root)
 }
 
-fn find_key_idx_not_eq(k1: &Key, k2: &Key) -> Option<usize> {
-    // todo: find the index at which the keys are not equal
-    for (idx, digit) in k1.iter().enumerate() {
-        if digit != &k2[idx] {
-            return Some(idx);
-        }
-    }
-    None
+// the index of the first bit at which the keys are not equal
+pub(crate) fn find_key_idx_not_eq(k1: &[u8], k2: &[u8]) -> Option<usize> {
+    k1.iter()
+        .zip(k2)
+        .position(|(a, b)| a != b)
+        .map(|byte_idx| byte_idx * 8 + (k1[byte_idx] ^ k2[byte_idx]).leading_zeros() as usize)
 }
 
 #[test]
 fn test_find_key_neq() {
-    let x = vec![0, 1, 1, 0, 0, 0, 1, 0, 0, 1, 0, 1, 1];
-    let y = vec![0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0, 1, 1];
+    let x = vec![0b0110_0010, 0b0101_1000];
+    let y = vec![0b0110_0010, 0b0001_1000];
     assert_eq!(find_key_idx_not_eq(&x, &y).unwrap(), 9);
+    assert_eq!(find_key_idx_not_eq(&x, &x), None);
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::merkle::tests::{generate_random_data, generate_random_key};
-    use crate::store::db::sql::TrieDB;
+    use crate::error::TrieError;
+    use crate::merkle::tests::{generate_random_data, generate_random_key, generate_random_leaves};
+    use crate::store::db::memory::InMemoryDB;
+    use crate::store::db::tests::TempDB;
+    use crate::store::db::Database;
+    use crate::store::hasher::{Hasher, Sha256Hasher};
     use crate::store::types::Leaf;
     use crate::store::types::{Hashable, Node, Root};
-    use crate::{check_leaf, insert_leaf};
+    use crate::trie::Trie;
+    use crate::{
+        check_leaf, get_leaf, insert_batch, insert_leaf, remove_leaf, update_leaf, upsert_leaf,
+        UpsertKind,
+    };
     use colored::*;
     use indicatif::ProgressBar;
-    use std::env;
     use std::time::Instant;
     #[test]
     fn test_insert_leaf() {
         let start_time = Instant::now();
-        let mut db = TrieDB {
-            path: env::var("PATH_TO_DB").unwrap_or("database.sqlite".to_string()),
-            cache: None,
-        };
-        db.setup();
-        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 256]);
-        let mut leaf_2_key: Vec<u8> = vec![0; 253];
-        for _i in 0..3 {
-            leaf_2_key.push(1);
-        }
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 32]);
+        let mut leaf_2_key: Vec<u8> = vec![0; 32];
+        leaf_2_key[31] = 0b111;
         let mut leaf_2: Leaf = Leaf::empty(leaf_2_key);
 
-        let mut leaf_3_key: Vec<u8> = vec![0; 253];
-        for _i in 0..3 {
-            leaf_3_key.push(0);
-        }
+        let leaf_3_key: Vec<u8> = vec![0; 32];
         let mut leaf_3 = Leaf::empty(leaf_3_key);
         leaf_1.hash();
         leaf_2.hash();
@@ -297,8 +695,8 @@ mod tests {
         let mut new_root = insert_leaf(&mut db, &mut leaf_1, root_node.clone()).unwrap();
         new_root = insert_leaf(&mut db, &mut leaf_2, Node::Root(new_root)).unwrap();
 
-        assert!(check_leaf(&mut db, &leaf_1, Node::Root(new_root.clone())));
-        assert!(check_leaf(&mut db, &leaf_2, Node::Root(new_root.clone())));
+        assert!(check_leaf(&mut db, &leaf_1, Node::Root(new_root.clone())).unwrap());
+        assert!(check_leaf(&mut db, &leaf_2, Node::Root(new_root.clone())).unwrap());
 
         println!(
             "{} Elapsed Time: {} µs",
@@ -307,6 +705,226 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_update_leaf() {
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::new(vec![0u8; 32], Some(vec![1]));
+        let mut leaf_2: Leaf = Leaf::new(vec![u8::MAX; 32], Some(vec![2]));
+        leaf_1.hash();
+        leaf_2.hash();
+        let root_node = Node::Root(Root::empty());
+        let old_root = insert_leaf(&mut db, &mut leaf_1, root_node).unwrap();
+        let old_root = insert_leaf(&mut db, &mut leaf_2, Node::Root(old_root)).unwrap();
+
+        let mut updated_leaf: Leaf = Leaf::new(vec![u8::MAX; 32], Some(vec![3]));
+        updated_leaf.hash();
+        let new_root =
+            update_leaf(&mut db, &mut updated_leaf, Node::Root(old_root.clone())).unwrap();
+        assert_ne!(new_root.hash, old_root.hash);
+        assert!(check_leaf(&mut db, &updated_leaf, Node::Root(new_root.clone())).unwrap());
+        assert!(check_leaf(&mut db, &leaf_1, Node::Root(new_root.clone())).unwrap());
+        assert!(!check_leaf(&mut db, &leaf_2, Node::Root(new_root.clone())).unwrap());
+        // the old root still points to the previous value
+        assert!(check_leaf(&mut db, &leaf_2, Node::Root(old_root.clone())).unwrap());
+
+        // updating a key that is not in the trie must fail
+        let mut missing_leaf: Leaf = Leaf::new(
+            vec![0u8; 31].into_iter().chain([1]).collect::<Vec<u8>>(),
+            None,
+        );
+        missing_leaf.hash();
+        assert!(update_leaf(&mut db, &mut missing_leaf, Node::Root(new_root)).is_err());
+    }
+
+    #[test]
+    fn test_remove_leaf() {
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 32]);
+        let mut leaf_2_key: Vec<u8> = vec![0; 32];
+        leaf_2_key[31] = 0b111;
+        let mut leaf_2: Leaf = Leaf::empty(leaf_2_key);
+        let mut leaf_3: Leaf = Leaf::empty(vec![u8::MAX; 32]);
+        leaf_1.hash();
+        leaf_2.hash();
+        leaf_3.hash();
+        let root_node = Node::Root(Root::empty());
+        let root_1_3 = insert_leaf(&mut db, &mut leaf_1, root_node).unwrap();
+        let root_1_3 = insert_leaf(&mut db, &mut leaf_3, Node::Root(root_1_3)).unwrap();
+        let full_root = insert_leaf(&mut db, &mut leaf_2, Node::Root(root_1_3.clone())).unwrap();
+
+        // removing leaf_2 collapses its parent branch into leaf_1
+        let new_root = remove_leaf(&mut db, &leaf_2.key, Node::Root(full_root.clone())).unwrap();
+        assert_eq!(new_root.hash, root_1_3.hash);
+        assert!(!check_leaf(&mut db, &leaf_2, Node::Root(new_root.clone())).unwrap());
+        assert!(check_leaf(&mut db, &leaf_1, Node::Root(new_root.clone())).unwrap());
+        assert!(check_leaf(&mut db, &leaf_2, Node::Root(full_root.clone())).unwrap());
+        assert!(remove_leaf(&mut db, &leaf_2.key, Node::Root(new_root.clone())).is_err());
+
+        // removing the last leaf on a side empties that side of the root
+        let new_root = remove_leaf(&mut db, &leaf_1.key, Node::Root(new_root)).unwrap();
+        assert!(new_root.left.is_none());
+        assert!(check_leaf(&mut db, &leaf_3, Node::Root(new_root.clone())).unwrap());
+        let new_root = remove_leaf(&mut db, &leaf_3.key, Node::Root(new_root)).unwrap();
+        assert!(new_root.left.is_none() && new_root.right.is_none());
+    }
+
+    #[test]
+    fn test_upsert_leaf() {
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::new(vec![0u8; 32], Some(vec![1]));
+        let mut leaf_2: Leaf = Leaf::new(vec![u8::MAX; 32], Some(vec![2]));
+        leaf_1.hash();
+        leaf_2.hash();
+        let root_node = Node::Root(Root::empty());
+        let (root, kind) = upsert_leaf(&mut db, &mut leaf_1, root_node).unwrap();
+        assert_eq!(kind, UpsertKind::Inserted);
+        let (root, kind) = upsert_leaf(&mut db, &mut leaf_2, Node::Root(root)).unwrap();
+        assert_eq!(kind, UpsertKind::Inserted);
+
+        let mut updated_leaf: Leaf = Leaf::new(vec![0u8; 32], Some(vec![3]));
+        updated_leaf.hash();
+        let (new_root, kind) =
+            upsert_leaf(&mut db, &mut updated_leaf, Node::Root(root.clone())).unwrap();
+        assert_eq!(kind, UpsertKind::Updated);
+        assert!(check_leaf(&mut db, &updated_leaf, Node::Root(new_root.clone())).unwrap());
+        assert!(check_leaf(&mut db, &leaf_2, Node::Root(new_root.clone())).unwrap());
+        assert!(check_leaf(&mut db, &leaf_1, Node::Root(root.clone())).unwrap());
+
+        // plain inserts still reject existing keys
+        assert!(insert_leaf(&mut db, &mut leaf_1, Node::Root(new_root)).is_err());
+    }
+
+    #[test]
+    fn test_get_leaf() {
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::new(vec![0u8; 32], Some(vec![1, 2, 3]));
+        let mut leaf_2: Leaf = Leaf::new(vec![u8::MAX; 32], Some(vec![4, 5, 6]));
+        leaf_1.hash();
+        leaf_2.hash();
+        let root_node = Node::Root(Root::empty());
+        assert_eq!(
+            get_leaf(&mut db, &leaf_1.key, root_node.clone()).unwrap(),
+            None
+        );
+        let root = insert_leaf(&mut db, &mut leaf_1, root_node).unwrap();
+        let root = insert_leaf(&mut db, &mut leaf_2, Node::Root(root)).unwrap();
+        let stored_leaf = get_leaf(&mut db, &leaf_1.key, Node::Root(root.clone()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_leaf.data, Some(vec![1, 2, 3]));
+        assert_eq!(stored_leaf, leaf_1);
+        // the path for this key ends at leaf_1, which has a different key
+        let mut missing_key: Vec<u8> = vec![0u8; 31];
+        missing_key.push(1);
+        assert_eq!(
+            get_leaf(&mut db, &missing_key, Node::Root(root)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::new(vec![0u8; 32], Some(vec![1]));
+        leaf_1.hash();
+        let root = insert_leaf(&mut db, &mut leaf_1, Node::Root(Root::empty())).unwrap();
+        assert!(matches!(
+            insert_leaf(&mut db, &mut leaf_1.clone(), Node::Root(root.clone())),
+            Err(TrieError::DuplicateLeaf { key }) if key == leaf_1.key
+        ));
+        assert!(matches!(
+            remove_leaf(&mut db, &[u8::MAX; 32], Node::Root(root)),
+            Err(TrieError::LeafNotFound { .. })
+        ));
+        assert!(matches!(
+            get_leaf(&mut db, &[0u8; 8], Node::Root(Root::empty())),
+            Err(TrieError::InvalidKeyLength { len: 8 })
+        ));
+        // a root that references a node which is not in the database
+        let mut corrupted_root = Root::empty();
+        corrupted_root.left = Some(vec![7u8; 32]);
+        assert!(matches!(
+            check_leaf(&mut db, &leaf_1, Node::Root(corrupted_root)),
+            Err(TrieError::MissingNode { hash, depth: 1 }) if hash == vec![7u8; 32]
+        ));
+        // an empty path would leave a root without children
+        assert!(matches!(
+            crate::update_modified_leafs(&mut db, Vec::new(), Root::empty(), &Sha256Hasher),
+            Err(TrieError::InvalidChild { depth: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_failed_insert_is_not_written() {
+        let temp_db = TempDB::new("failed-insert");
+        let mut db = temp_db.open();
+        let mut existing_leaf: Leaf = Leaf::new(vec![u8::MAX; 32], Some(vec![1]));
+        existing_leaf.hash();
+        let root = insert_leaf(&mut db, &mut existing_leaf, Node::Root(Root::empty())).unwrap();
+        // the new leaf is stored before the duplicate on the right side is found
+        let mut new_leaf: Leaf = Leaf::new(vec![0u8; 32], Some(generate_random_data()));
+        new_leaf.hash();
+        assert!(matches!(
+            insert_batch(
+                &mut db,
+                vec![new_leaf.clone(), existing_leaf],
+                Node::Root(root)
+            ),
+            Err(TrieError::DuplicateLeaf { .. })
+        ));
+        assert!(db.get(&new_leaf.hash.unwrap()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_insert_order_independent() {
+        let mut db = InMemoryDB::new();
+        let leafs: Vec<Leaf> = generate_random_leaves(50);
+        let mut forward_root = Node::Root(Root::empty());
+        for leaf in leafs.iter() {
+            forward_root =
+                Node::Root(insert_leaf(&mut db, &mut leaf.clone(), forward_root).unwrap());
+        }
+        let mut backward_root = Node::Root(Root::empty());
+        for leaf in leafs.iter().rev() {
+            backward_root =
+                Node::Root(insert_leaf(&mut db, &mut leaf.clone(), backward_root).unwrap());
+        }
+        assert_eq!(
+            forward_root.unwrap_as_root().unwrap().hash,
+            backward_root.unwrap_as_root().unwrap().hash
+        );
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let mut db = InMemoryDB::new();
+        let leafs: Vec<Leaf> = generate_random_leaves(200);
+        let mut sequential_root = Node::Root(Root::empty());
+        for leaf in leafs.iter() {
+            sequential_root =
+                Node::Root(insert_leaf(&mut db, &mut leaf.clone(), sequential_root).unwrap());
+        }
+        let sequential_root = sequential_root.unwrap_as_root().unwrap();
+
+        // batch into an empty trie
+        let batch_root = insert_batch(&mut db, leafs.clone(), Node::Root(Root::empty())).unwrap();
+        assert_eq!(batch_root.hash, sequential_root.hash);
+
+        // batch into a trie that already holds half of the leafs
+        let mut half_root = Node::Root(Root::empty());
+        for leaf in leafs[..100].iter() {
+            half_root = Node::Root(insert_leaf(&mut db, &mut leaf.clone(), half_root).unwrap());
+        }
+        let batch_root = insert_batch(&mut db, leafs[100..].to_vec(), half_root.clone()).unwrap();
+        assert_eq!(batch_root.hash, sequential_root.hash);
+        for leaf in leafs.iter() {
+            assert!(check_leaf(&mut db, leaf, Node::Root(batch_root.clone())).unwrap());
+        }
+
+        // existing keys are rejected
+        assert!(insert_batch(&mut db, leafs[..1].to_vec(), half_root).is_err());
+    }
+
     #[test]
     fn test_many_leafs() {
         let transaction_count: u32 = std::env::var("INSERT_TRANSACTION_COUNT")
@@ -320,21 +938,15 @@ mod tests {
             transactions.push(leaf);
         }
         let start_time = Instant::now();
-        let mut db = TrieDB {
-            path: env::var("PATH_TO_DB").unwrap_or("database.sqlite".to_string()),
-            cache: None,
-        };
+        let temp_db = TempDB::new("many-leafs");
+        let mut db = temp_db.open_or_env();
         let root: Root = Root::empty();
         let mut root_node = Node::Root(root);
         let progress_bar: ProgressBar = ProgressBar::new(transaction_count as u64);
         for mut leaf in transactions {
             leaf.hash();
             let new_root = insert_leaf(&mut db, &mut leaf, root_node.clone()).unwrap();
-            assert!(check_leaf(
-                &mut db,
-                &leaf.clone(),
-                Node::Root(new_root.clone())
-            ));
+            assert!(check_leaf(&mut db, &leaf.clone(), Node::Root(new_root.clone())).unwrap());
             root_node = Node::Root(new_root.clone());
             progress_bar.inc(1);
         }
@@ -349,24 +961,15 @@ mod tests {
     #[test]
     fn test_sql_db() {
         use crate::merkle::{merkle_proof, verify_merkle_proof};
-        use crate::store::db::sql::TrieDB;
 
         let start_time = Instant::now();
-        let mut db = TrieDB {
-            path: env::var("PATH_TO_DB").unwrap_or("database.sqlite".to_string()),
-            cache: None,
-        };
-        db.setup();
-        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 256]);
-        let mut leaf_2_key: Vec<u8> = vec![0; 253];
-        for _i in 0..3 {
-            leaf_2_key.push(1);
-        }
+        let temp_db = TempDB::new("sql-db");
+        let mut db = temp_db.open_or_env();
+        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 32]);
+        let mut leaf_2_key: Vec<u8> = vec![0; 32];
+        leaf_2_key[31] = 0b111;
         let mut leaf_2: Leaf = Leaf::empty(leaf_2_key);
-        let mut leaf_3_key: Vec<u8> = vec![0; 253];
-        for _i in 0..3 {
-            leaf_3_key.push(0);
-        }
+        let leaf_3_key: Vec<u8> = vec![0; 32];
         let mut leaf_3 = Leaf::empty(leaf_3_key);
         leaf_1.hash();
         leaf_2.hash();
@@ -380,13 +983,124 @@ mod tests {
             "[1x Insert]".yellow(),
             &start_time.elapsed().as_micros().to_string().blue()
         );
-        let mut leaf_1: Leaf = Leaf::empty(vec![1u8; 256]);
+        let mut leaf_1: Leaf = Leaf::empty(vec![u8::MAX; 32]);
         leaf_1.hash();
         let root: Root = new_root.unwrap();
         let root_node: Node = Node::Root(root);
         let new_root: Root = insert_leaf(&mut db, &mut leaf_1, root_node).unwrap();
-        let proof = merkle_proof(&mut db, leaf_1.key, Node::Root(new_root.clone()));
+        let proof = merkle_proof(&mut db, &leaf_1.key, Node::Root(new_root.clone()));
         let inner_proof = proof.unwrap().nodes;
-        verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap();
+        assert!(verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_in_memory_db() {
+        let mut memory_db = InMemoryDB::new();
+        // the sqlite backend is the reference, but on a file of its own
+        let temp_db = TempDB::new("in-memory");
+        let mut sql_db = temp_db.open();
+        let leafs: Vec<Leaf> = generate_random_leaves(20);
+        let memory_root =
+            insert_batch(&mut memory_db, leafs.clone(), Node::Root(Root::empty())).unwrap();
+        let sql_root = insert_batch(&mut sql_db, leafs.clone(), Node::Root(Root::empty())).unwrap();
+        // both backends build the same trie
+        assert_eq!(memory_root.hash, sql_root.hash);
+        for leaf in &leafs {
+            assert!(check_leaf(&mut memory_db, leaf, Node::Root(memory_root.clone())).unwrap());
+        }
+        let root_hash = memory_root.hash.unwrap();
+        assert!(matches!(
+            memory_db.get(&root_hash).unwrap(),
+            Some(Node::Root(root)) if root.hash == Some(root_hash.clone())
+        ));
+    }
+
+    fn check_hasher<H: Hasher + Copy>(hasher: H) {
+        use crate::insert_batch_with;
+        use crate::merkle::{
+            merkle_proof_compact, verify_compact_proof_with, verify_merkle_proof,
+            verify_merkle_proof_with,
+        };
+        use crate::store::types::default_hash;
+
+        let mut trie = Trie::with_hasher(InMemoryDB::new(), hasher).unwrap();
+        let mut leafs: Vec<Leaf> = generate_random_leaves(20);
+        for leaf in leafs.iter_mut() {
+            trie.insert(leaf.key.clone(), leaf.data.clone().unwrap())
+                .unwrap();
+            leaf.hash_with(&hasher);
+        }
+        let root_hash = trie.root_hash().clone();
+        let root_node = Node::Root(trie.root().clone());
+        for leaf in leafs.iter() {
+            let proof = trie.prove(&leaf.key).unwrap();
+            assert!(
+                verify_merkle_proof_with(proof.nodes.clone(), root_hash.clone(), &hasher).unwrap()
+            );
+            // proofs only verify with the hasher that built the trie
+            assert_eq!(
+                verify_merkle_proof(proof.nodes, root_hash.clone()).unwrap(),
+                hasher.digest(&[]) == default_hash([])
+            );
+            let proof = merkle_proof_compact(trie.db(), &leaf.key, root_node.clone()).unwrap();
+            assert!(verify_compact_proof_with(proof, root_hash.clone(), &hasher).unwrap());
+        }
+        let batch_root = insert_batch_with(
+            &mut InMemoryDB::new(),
+            leafs,
+            Node::Root(Root::empty()),
+            &hasher,
+        )
+        .unwrap();
+        assert_eq!(batch_root.hash, Some(root_hash));
+    }
+
+    #[test]
+    fn test_sha256_hasher() {
+        check_hasher(Sha256Hasher);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3_hasher() {
+        use crate::store::hasher::Blake3Hasher;
+        let empty_hash =
+            hex::decode("af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262")
+                .unwrap();
+        assert_eq!(Blake3Hasher.digest(&[]), empty_hash);
+        check_hasher(Blake3Hasher);
+    }
+
+    #[cfg(feature = "keccak")]
+    #[test]
+    fn test_keccak_hasher() {
+        use crate::store::hasher::Keccak256Hasher;
+        let empty_hash =
+            hex::decode("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470")
+                .unwrap();
+        assert_eq!(Keccak256Hasher.digest(&[]), empty_hash);
+        check_hasher(Keccak256Hasher);
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn test_poseidon_hasher() {
+        use crate::store::hasher::PoseidonHasher;
+        use crate::store::types::Branch;
+
+        let mut branch = Branch::new(3, Some(vec![1]), Some(vec![2]));
+        branch.hash_with(&PoseidonHasher);
+        let mut swapped_branch = Branch::new(3, Some(vec![2]), Some(vec![1]));
+        swapped_branch.hash_with(&PoseidonHasher);
+        assert_ne!(branch.hash, swapped_branch.hash);
+        // the split index is an input of the hash as well
+        let mut first_bit_branch = Branch::new(0, Some(vec![1]), Some(vec![2]));
+        first_bit_branch.hash_with(&PoseidonHasher);
+        assert_ne!(branch.hash, first_bit_branch.hash);
+        // every hash is a field element, so that it can be used as an input again
+        let branch_hash = branch.hash.unwrap();
+        assert_eq!(branch_hash.len(), 32);
+        assert!(branch_hash[0] < 0x31);
+        check_hasher(PoseidonHasher);
     }
 }
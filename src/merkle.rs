@@ -1,84 +1,600 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
 // Compute Merkle Proof for a Leaf at a given point in time (e.g. at a Snapshot)
-use crate::store::{
-    db::Database,
-    types::{Hashable, Node, NodeHash, RootHash},
+use crate::{
+    check_key,
+    error::{ProofError, Result, TrieError},
+    find_key_idx_not_eq,
+    iter::{iter, last_before},
+    load_child, load_node, node_hash,
+    store::{
+        db::Database,
+        hasher::{Hasher, Sha256Hasher},
+        types::{key_bit, Branch, Hashable, Key, Leaf, Node, NodeHash, Root, RootHash, KEY_LENGTH},
+    },
 };
-use anyhow::{bail, Result};
 // obtain the merkle path for a leaf
-pub fn merkle_proof(db: &mut dyn Database, key: Vec<u8>, trie_root: Node) -> Result<MerkleProof> {
-    assert_eq!(key.len(), 256);
+pub fn merkle_proof(db: &mut dyn Database, key: &[u8], trie_root: Node) -> Result<MerkleProof> {
+    check_key(key)?;
     let mut proof: MerkleProof = MerkleProof { nodes: Vec::new() };
     let mut current_node = trie_root.clone();
+    let mut depth: usize = 0;
     loop {
         match &mut current_node {
             Node::Root(root) => {
                 proof.nodes.push((false, Node::Root(root.clone())));
-                if key[0] == 0 {
-                    let left_child = db.get(&root.left.clone().unwrap()).unwrap();
-                    current_node = left_child.clone();
-                    proof.nodes.push((false, left_child.clone()));
+                let child = if key_bit(key, 0) == 0 {
+                    &root.left
                 } else {
-                    let right_child = db.get(&root.right.clone().unwrap()).unwrap();
-                    current_node = right_child.clone();
-                    proof.nodes.push((true, right_child.clone()));
-                }
+                    &root.right
+                };
+                let child = match child {
+                    Some(node_hash) => load_node(db, node_hash, depth + 1)?,
+                    None => return Err(TrieError::LeafNotFound { key: key.to_vec() }),
+                };
+                proof.nodes.push((key_bit(key, 0) == 1, child.clone()));
+                current_node = child;
             }
             Node::Branch(branch) => {
-                let digit = key[branch.key[0] as usize];
-                if digit == 0 {
-                    current_node = db.get(&branch.left.clone().unwrap()).unwrap().clone();
-                    proof.nodes.push((false, current_node.clone()));
-                } else {
-                    current_node = db.get(&branch.right.clone().unwrap()).unwrap().clone();
-                    proof.nodes.push((true, current_node.clone()));
+                let digit = key_bit(key, branch.split_idx() as usize);
+                current_node = load_child(db, branch, digit, depth)?;
+                proof.nodes.push((digit == 1, current_node.clone()));
+            }
+            Node::Leaf(leaf) => {
+                if leaf.key != key {
+                    return Err(TrieError::LeafNotFound { key: key.to_vec() });
                 }
+                return Ok(proof);
             }
-            Node::Leaf(_) => return Ok(proof),
         }
+        depth += 1;
     }
 }
 
+// returns Ok(false) if a well-formed proof does not match the root hash
+// and an error if the proof is malformed
 pub fn verify_merkle_proof(
-    mut inner_proof: Vec<(bool, Node)>,
+    inner_proof: Vec<(bool, Node)>,
+    state_root_hash: RootHash,
+) -> std::result::Result<bool, ProofError> {
+    verify_merkle_proof_with(inner_proof, state_root_hash, &Sha256Hasher)
+}
+
+// the *_with verifiers recompute every hash with the hasher that built the trie
+pub fn verify_merkle_proof_with(
+    inner_proof: Vec<(bool, Node)>,
     state_root_hash: RootHash,
-) -> Result<()> {
+    hasher: &dyn Hasher,
+) -> std::result::Result<bool, ProofError> {
+    let key = match inner_proof.last() {
+        Some((_, Node::Leaf(leaf))) => leaf.key.clone(),
+        Some(_) => return Err(ProofError::MissingLeaf),
+        None => return Err(ProofError::EmptyProof),
+    };
+    check_proof_path(&inner_proof, &key)?;
+    let root_hash = compute_proof_root_hash(inner_proof, hasher)?;
+    // the merkle proof is valid for the given root hash
+    // if the path hashes to it
+    Ok(state_root_hash == root_hash)
+}
+
+// check that a path starts at a root, only ends in a leaf and follows the bits of the key
+fn check_proof_path(nodes: &[(bool, Node)], key: &[u8]) -> std::result::Result<(), ProofError> {
+    if key.len() != KEY_LENGTH {
+        return Err(ProofError::InvalidKeyLength { len: key.len() });
+    }
+    match nodes.first() {
+        Some((_, Node::Root(_))) => {}
+        Some(_) => return Err(ProofError::MissingRoot),
+        None => return Err(ProofError::EmptyProof),
+    }
+    for depth in 1..nodes.len() {
+        let expected_bit = match &nodes[depth - 1].1 {
+            Node::Root(_) if depth == 1 => key_bit(key, 0),
+            Node::Root(_) => return Err(ProofError::UnexpectedRoot { depth: depth - 1 }),
+            Node::Branch(branch) => key_bit(key, branch.split_idx() as usize),
+            Node::Leaf(_) => return Err(ProofError::UnexpectedLeaf { depth: depth - 1 }),
+        };
+        if nodes[depth].0 != (expected_bit == 1) {
+            return Err(ProofError::PathMismatch { depth });
+        }
+    }
+    if let Some((_, Node::Root(_))) = nodes.get(1..).and_then(|nodes| nodes.last()) {
+        return Err(ProofError::UnexpectedRoot {
+            depth: nodes.len() - 1,
+        });
+    }
+    Ok(())
+}
+
+// hash the path from the leaf back up to the root
+fn compute_proof_root_hash(
+    mut inner_proof: Vec<(bool, Node)>,
+    hasher: &dyn Hasher,
+) -> std::result::Result<RootHash, ProofError> {
     inner_proof.reverse();
+    let depth = inner_proof.len();
     let mut current_hash: Option<(bool, NodeHash)> = None;
     let mut root_hash: Option<RootHash> = None;
     for (idx, node) in inner_proof.into_iter().enumerate() {
-        if idx == 0 {
-            let leaf = node.1.unwrap_as_leaf()?;
-            current_hash = Some((node.0, leaf.hash.unwrap()));
-        } else {
-            match node.1 {
-                Node::Root(mut root) => {
-                    if !current_hash.clone().unwrap().0 {
-                        root.left = Some(current_hash.clone().unwrap().1);
-                    } else {
-                        root.right = Some(current_hash.clone().unwrap().1);
+        let (is_right, child_hash) = match (idx, &current_hash) {
+            (0, _) => {
+                let mut leaf = match node.1 {
+                    Node::Leaf(leaf) => leaf,
+                    _ => return Err(ProofError::MissingLeaf),
+                };
+                // never trust the hash that was sent along with the leaf
+                leaf.hash_with(hasher);
+                current_hash = Some((node.0, leaf.hash.unwrap()));
+                continue;
+            }
+            (_, Some(current_hash)) => current_hash.clone(),
+            (_, None) => return Err(ProofError::UnexpectedRoot { depth: depth - idx }),
+        };
+        match node.1 {
+            Node::Root(mut root) => {
+                if !is_right {
+                    root.left = Some(child_hash);
+                } else {
+                    root.right = Some(child_hash);
+                }
+                root.hash_with(hasher);
+                root_hash = root.hash;
+                current_hash = None;
+            }
+            Node::Branch(mut branch) => {
+                if !is_right {
+                    branch.left = Some(child_hash);
+                } else {
+                    branch.right = Some(child_hash);
+                }
+                branch.hash_with(hasher);
+                current_hash = Some((node.0, branch.hash.unwrap()));
+            }
+            Node::Leaf(_) => {
+                return Err(ProofError::UnexpectedLeaf {
+                    depth: depth - 1 - idx,
+                })
+            }
+        }
+    }
+    root_hash.ok_or(ProofError::MissingRoot)
+}
+
+// obtain a compact merkle proof that only carries the sibling hashes along the path
+pub fn merkle_proof_compact(
+    db: &mut dyn Database,
+    key: &[u8],
+    trie_root: Node,
+) -> Result<CompactMerkleProof> {
+    let mut nodes = merkle_proof(db, key, trie_root)?.nodes;
+    let (leaf_direction, leaf) = match nodes.pop() {
+        Some((direction, Node::Leaf(leaf))) => (direction, leaf),
+        _ => return Err(TrieError::InvalidProof(ProofError::MissingLeaf)),
+    };
+    let mut directions: Vec<bool> = Vec::new();
+    let mut split_indices: Vec<u8> = Vec::new();
+    let mut siblings: Vec<NodeHash> = Vec::new();
+    let mut root_sibling: Option<NodeHash> = None;
+    // the direction taken at each node is stored with its child
+    let child_directions: Vec<bool> = nodes
+        .iter()
+        .skip(1)
+        .map(|(direction, _)| *direction)
+        .chain(std::iter::once(leaf_direction))
+        .collect();
+    for ((_, node), direction) in nodes.into_iter().zip(child_directions) {
+        directions.push(direction);
+        match node {
+            Node::Root(root) => {
+                root_sibling = if direction { root.left } else { root.right };
+            }
+            Node::Branch(branch) => {
+                split_indices.push(branch.split_idx());
+                let sibling = if direction { branch.left } else { branch.right };
+                match sibling {
+                    Some(sibling) => siblings.push(sibling),
+                    None => {
+                        return Err(TrieError::InvalidBranch {
+                            hash: branch.hash,
+                            depth: directions.len() - 1,
+                        })
                     }
-                    root.hash();
-                    root_hash = root.hash;
                 }
-                Node::Branch(mut branch) => {
-                    if !current_hash.clone().unwrap().0 {
-                        branch.left = Some(current_hash.clone().unwrap().1);
+            }
+            Node::Leaf(_) => {
+                return Err(TrieError::InvalidParent {
+                    depth: directions.len() - 1,
+                })
+            }
+        }
+    }
+    Ok(CompactMerkleProof {
+        leaf,
+        directions,
+        split_indices,
+        root_sibling,
+        siblings,
+    })
+}
+
+pub fn verify_compact_proof(
+    proof: CompactMerkleProof,
+    state_root_hash: RootHash,
+) -> std::result::Result<bool, ProofError> {
+    verify_compact_proof_with(proof, state_root_hash, &Sha256Hasher)
+}
+
+pub fn verify_compact_proof_with(
+    proof: CompactMerkleProof,
+    state_root_hash: RootHash,
+    hasher: &dyn Hasher,
+) -> std::result::Result<bool, ProofError> {
+    let key = &proof.leaf.key;
+    if key.len() != KEY_LENGTH {
+        return Err(ProofError::InvalidKeyLength { len: key.len() });
+    }
+    if proof.directions.len() != proof.split_indices.len() + 1
+        || proof.siblings.len() != proof.split_indices.len()
+    {
+        return Err(ProofError::LengthMismatch);
+    }
+    // every step of the path must follow the bits of the leaf key
+    let expected_directions = std::iter::once(key_bit(key, 0) == 1).chain(
+        proof
+            .split_indices
+            .iter()
+            .map(|split_idx| key_bit(key, *split_idx as usize) == 1),
+    );
+    for (depth, (expected, direction)) in
+        expected_directions.zip(proof.directions.iter()).enumerate()
+    {
+        if expected != *direction {
+            return Err(ProofError::PathMismatch { depth: depth + 1 });
+        }
+    }
+    let mut leaf = proof.leaf.clone();
+    leaf.hash_with(hasher);
+    let mut current_hash: NodeHash = leaf.hash.unwrap();
+    for idx in (0..proof.split_indices.len()).rev() {
+        let sibling = Some(proof.siblings[idx].clone());
+        let mut branch = Branch::empty(proof.split_indices[idx]);
+        if proof.directions[idx + 1] {
+            branch.update(sibling, Some(current_hash));
+        } else {
+            branch.update(Some(current_hash), sibling);
+        }
+        branch.hash_with(hasher);
+        current_hash = branch.hash.unwrap();
+    }
+    let mut root = Root::empty();
+    if proof.directions[0] {
+        root.left = proof.root_sibling;
+        root.right = Some(current_hash);
+    } else {
+        root.left = Some(current_hash);
+        root.right = proof.root_sibling;
+    }
+    root.hash_with(hasher);
+    Ok(root.hash == Some(state_root_hash))
+}
+
+// obtain one proof for many keys, nodes that are shared between the paths are only included once
+pub fn multi_merkle_proof(
+    db: &mut dyn Database,
+    keys: Vec<Key>,
+    trie_root: Node,
+) -> Result<MultiProof> {
+    let mut seen: HashSet<NodeHash> = HashSet::new();
+    let mut nodes: Vec<Node> = Vec::new();
+    for key in keys.iter() {
+        for (_, node) in merkle_proof(db, key, trie_root.clone())?.nodes {
+            if seen.insert(node_hash(&node)?) {
+                nodes.push(node);
+            }
+        }
+    }
+    Ok(MultiProof { keys, nodes })
+}
+
+pub fn verify_multi_proof(
+    proof: MultiProof,
+    state_root_hash: RootHash,
+) -> std::result::Result<bool, ProofError> {
+    verify_multi_proof_with(proof, state_root_hash, &Sha256Hasher)
+}
+
+pub fn verify_multi_proof_with(
+    proof: MultiProof,
+    state_root_hash: RootHash,
+    hasher: &dyn Hasher,
+) -> std::result::Result<bool, ProofError> {
+    // every node is indexed by the hash of its own content, so a child can
+    // only be found if its parent commits to it
+    let nodes: HashMap<NodeHash, Node> = proof
+        .nodes
+        .into_iter()
+        .map(|node| (recompute_node_hash(&node, hasher), node))
+        .collect();
+    let root = match nodes.get(&state_root_hash) {
+        Some(Node::Root(root)) => root,
+        _ if nodes.values().any(|node| matches!(node, Node::Root(_))) => return Ok(false),
+        _ => return Err(ProofError::MissingRoot),
+    };
+    let mut reached: HashSet<&NodeHash> = HashSet::from([&state_root_hash]);
+    for key in proof.keys.iter() {
+        if key.len() != KEY_LENGTH {
+            return Err(ProofError::InvalidKeyLength { len: key.len() });
+        }
+        let mut current_hash = if key_bit(key, 0) == 0 {
+            root.left.clone()
+        } else {
+            root.right.clone()
+        };
+        let mut depth: usize = 1;
+        loop {
+            let (node_hash, node) = match current_hash
+                .as_ref()
+                .and_then(|hash| nodes.get_key_value(hash))
+            {
+                Some(entry) => entry,
+                None => return Err(ProofError::MissingNode { depth }),
+            };
+            reached.insert(node_hash);
+            match node {
+                Node::Branch(branch) => {
+                    current_hash = if key_bit(key, branch.split_idx() as usize) == 0 {
+                        branch.left.clone()
                     } else {
-                        branch.right = Some(current_hash.clone().unwrap().1);
-                    }
-                    branch.hash();
-                    current_hash = Some((node.0, branch.hash.unwrap()));
+                        branch.right.clone()
+                    };
                 }
-                Node::Leaf(_) => bail!("Invalid Node variant in Merkle Proof"),
+                // the path of the key ends at a different leaf
+                Node::Leaf(leaf) if &leaf.key != key => return Ok(false),
+                Node::Leaf(_) => break,
+                Node::Root(_) => return Err(ProofError::UnexpectedRoot { depth }),
             }
+            depth += 1;
         }
     }
-    // if this assertion passes, the merkle proof is valid
-    // for the given root hash
-    assert_eq!(&state_root_hash, &root_hash.unwrap());
-    Ok(())
+    // nodes off the paths of the keys are not verified, a caller must not be handed them
+    if reached.len() != nodes.len() {
+        return Err(ProofError::UnreachableNode);
+    }
+    Ok(true)
+}
+
+fn recompute_node_hash(node: &Node, hasher: &dyn Hasher) -> NodeHash {
+    match node.clone() {
+        Node::Root(mut root) => {
+            root.hash_with(hasher);
+            root.hash.unwrap()
+        }
+        Node::Branch(mut branch) => {
+            branch.hash_with(hasher);
+            branch.hash.unwrap()
+        }
+        Node::Leaf(mut leaf) => {
+            leaf.hash_with(hasher);
+            leaf.hash.unwrap()
+        }
+    }
+}
+
+// obtain a proof that a key is not present in the trie, the path for the key
+// either ends at an empty side of the root or at a leaf with a different key
+pub fn exclusion_proof(
+    db: &mut dyn Database,
+    key: &[u8],
+    trie_root: Node,
+) -> Result<ExclusionProof> {
+    check_key(key)?;
+    let key = key.to_vec();
+    let root = trie_root.unwrap_as_root()?;
+    let mut nodes: Vec<(bool, Node)> = vec![(false, Node::Root(root.clone()))];
+    let mut current_pos: bool = key_bit(&key, 0) == 1;
+    let mut current_node = match if current_pos { root.right } else { root.left } {
+        Some(node_hash) => load_node(db, &node_hash, 1)?,
+        None => {
+            return Ok(ExclusionProof {
+                key,
+                nodes,
+                conflicting_leaf: None,
+                neq_idx: None,
+            })
+        }
+    };
+    let mut depth: usize = 1;
+    loop {
+        nodes.push((current_pos, current_node.clone()));
+        match current_node {
+            Node::Branch(branch) => {
+                let digit = key_bit(&key, branch.split_idx() as usize);
+                current_pos = digit == 1;
+                current_node = load_child(db, &branch, digit, depth)?;
+            }
+            Node::Leaf(leaf) => {
+                let neq_idx = match find_key_idx_not_eq(&key, &leaf.key) {
+                    Some(neq_idx) => neq_idx,
+                    None => return Err(TrieError::DuplicateLeaf { key }),
+                };
+                return Ok(ExclusionProof {
+                    key,
+                    nodes,
+                    conflicting_leaf: Some(leaf),
+                    neq_idx: Some(neq_idx),
+                });
+            }
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+        depth += 1;
+    }
+}
+
+pub fn verify_exclusion_proof(
+    proof: ExclusionProof,
+    state_root_hash: RootHash,
+) -> std::result::Result<bool, ProofError> {
+    verify_exclusion_proof_with(proof, state_root_hash, &Sha256Hasher)
+}
+
+pub fn verify_exclusion_proof_with(
+    proof: ExclusionProof,
+    state_root_hash: RootHash,
+    hasher: &dyn Hasher,
+) -> std::result::Result<bool, ProofError> {
+    let key = proof.key;
+    // every step of the path must follow the bits of the excluded key
+    check_proof_path(&proof.nodes, &key)?;
+    match (proof.nodes.last(), proof.conflicting_leaf, proof.neq_idx) {
+        // the side of the root that the key would be stored under is empty
+        (Some((_, Node::Root(root))), None, None) => {
+            let side = if key_bit(&key, 0) == 0 {
+                &root.left
+            } else {
+                &root.right
+            };
+            if side.is_some() {
+                return Err(ProofError::NonEmptyRoot);
+            }
+            let mut root = root.clone();
+            root.hash_with(hasher);
+            Ok(root.hash == Some(state_root_hash))
+        }
+        // the path ends at a leaf that diverges from the key
+        (Some((_, Node::Leaf(leaf))), Some(conflicting_leaf), Some(neq_idx)) => {
+            if leaf != &conflicting_leaf
+                || find_key_idx_not_eq(&key, &conflicting_leaf.key) != Some(neq_idx)
+            {
+                return Err(ProofError::ConflictingLeaf);
+            }
+            Ok(compute_proof_root_hash(proof.nodes, hasher)? == state_root_hash)
+        }
+        (Some((_, Node::Branch(_))), _, _) => Err(ProofError::MissingLeaf),
+        _ => Err(ProofError::ConflictingLeaf),
+    }
+}
+
+// obtain a proof that a list of leaves holds every key in [start, end). It consists of the
+// paths to those leaves and to the closest leaves outside the range on either side, every
+// subtree that is not part of the proof then lies entirely before or after the range
+pub fn range_proof(
+    db: &mut dyn Database,
+    start: &[u8],
+    end: &[u8],
+    trie_root: Node,
+) -> Result<RangeProof> {
+    check_key(start)?;
+    check_key(end)?;
+    if start >= end {
+        return Err(TrieError::InvalidRange {
+            start: start.to_vec(),
+            end: end.to_vec(),
+        });
+    }
+    let root: Root = trie_root.clone().unwrap_as_root()?;
+    let mut keys: Vec<Key> = Vec::new();
+    if let Some(before) = last_before(db, &root, start)? {
+        keys.push(before.key);
+    }
+    let mut leaves = iter(db, trie_root.clone())?;
+    leaves.seek(start)?;
+    for leaf in leaves {
+        let leaf = leaf?;
+        let is_after = leaf.key.as_slice() >= end;
+        keys.push(leaf.key);
+        if is_after {
+            break;
+        }
+    }
+    let nodes: Vec<Node> = match keys.is_empty() {
+        // only an empty trie has no leaf on either side
+        true => vec![Node::Root(root)],
+        false => multi_merkle_proof(db, keys, trie_root)?.nodes,
+    };
+    Ok(RangeProof {
+        start: start.to_vec(),
+        end: end.to_vec(),
+        nodes,
+    })
+}
+
+// the leaves must be exactly the leaves of the range in ascending key order
+pub fn verify_range_proof(
+    proof: RangeProof,
+    leaves: &[Leaf],
+    state_root_hash: RootHash,
+) -> std::result::Result<bool, ProofError> {
+    verify_range_proof_with(proof, leaves, state_root_hash, &Sha256Hasher)
+}
+
+pub fn verify_range_proof_with(
+    proof: RangeProof,
+    leaves: &[Leaf],
+    state_root_hash: RootHash,
+    hasher: &dyn Hasher,
+) -> std::result::Result<bool, ProofError> {
+    for key in [&proof.start, &proof.end] {
+        if key.len() != KEY_LENGTH {
+            return Err(ProofError::InvalidKeyLength { len: key.len() });
+        }
+    }
+    if proof.start >= proof.end {
+        return Err(ProofError::InvalidRange);
+    }
+    let nodes: HashMap<NodeHash, Node> = proof
+        .nodes
+        .into_iter()
+        .map(|node| (recompute_node_hash(&node, hasher), node))
+        .collect();
+    let root = match nodes.get(&state_root_hash) {
+        Some(Node::Root(root)) => root,
+        _ if nodes.values().any(|node| matches!(node, Node::Root(_))) => return Ok(false),
+        _ => return Err(ProofError::MissingRoot),
+    };
+    // walk the nodes of the proof in key order, None is a subtree that is not part of it
+    let mut walked: Vec<Option<&Leaf>> = Vec::new();
+    let mut pending: Vec<(&NodeHash, usize)> = [&root.right, &root.left]
+        .into_iter()
+        .flatten()
+        .map(|node_hash| (node_hash, 1))
+        .collect();
+    while let Some((node_hash, depth)) = pending.pop() {
+        match nodes.get(node_hash) {
+            Some(Node::Branch(branch)) => match (&branch.left, &branch.right) {
+                (Some(left), Some(right)) => {
+                    pending.push((right, depth + 1));
+                    pending.push((left, depth + 1));
+                }
+                _ => return Err(ProofError::InvalidBranch { depth }),
+            },
+            Some(Node::Leaf(leaf)) => walked.push(Some(leaf)),
+            Some(Node::Root(_)) => return Err(ProofError::UnexpectedRoot { depth }),
+            None => walked.push(None),
+        }
+    }
+    // the keys of an omitted subtree lie between the leaves next to it, it must
+    // end before the range starts or begin after the range ends
+    for (idx, _) in walked.iter().enumerate().filter(|(_, item)| item.is_none()) {
+        let before = walked[..idx].iter().rev().flatten().next();
+        let after = walked[idx + 1..].iter().flatten().next();
+        let is_before = after.is_some_and(|leaf| leaf.key <= proof.start);
+        let is_after = before.is_some_and(|leaf| leaf.key >= proof.end);
+        if !is_before && !is_after {
+            return Err(ProofError::IncompleteRange);
+        }
+    }
+    let in_range: Vec<&Leaf> = walked
+        .into_iter()
+        .flatten()
+        .filter(|leaf| leaf.key >= proof.start && leaf.key < proof.end)
+        .collect();
+    // the cached hash of a leaf is not part of its content, it is never compared
+    Ok(in_range.len() == leaves.len()
+        && in_range.into_iter().zip(leaves).all(|(proven, leaf)| {
+            proven.key == leaf.key && proven.data == leaf.data && proven.preimage == leaf.preimage
+        }))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -86,12 +602,57 @@ pub struct MerkleProof {
     pub nodes: Vec<(bool, Node)>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompactMerkleProof {
+    pub leaf: Leaf,
+    // the direction taken at the root and at every branch, true is right
+    pub directions: Vec<bool>,
+    // the split index of every branch on the path, from the top down
+    pub split_indices: Vec<u8>,
+    // the other side of the root, None if it is empty
+    pub root_sibling: Option<NodeHash>,
+    // the hash of the child that is not on the path, for every branch
+    pub siblings: Vec<NodeHash>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MultiProof {
+    pub keys: Vec<Key>,
+    // the union of all nodes on the paths of the keys, root first
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RangeProof {
+    // the range is [start, end)
+    pub start: Key,
+    pub end: Key,
+    // the union of the paths to every leaf in the range and to the closest
+    // leaf before and after it, root first
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExclusionProof {
+    // the key that is not in the trie
+    pub key: Key,
+    pub nodes: Vec<(bool, Node)>,
+    // the leaf at the end of the path, None if the side of the root is empty
+    pub conflicting_leaf: Option<Leaf>,
+    // the first index at which the key and the conflicting leaf key differ
+    pub neq_idx: Option<usize>,
+}
+
 #[cfg(test)]
 pub mod tests {
-    use crate::store::db::sql::TrieDB;
+    use crate::store::db::memory::InMemoryDB;
     use crate::{
+        error::ProofError,
         insert_leaf,
-        merkle::verify_merkle_proof,
+        merkle::{
+            exclusion_proof, merkle_proof_compact, multi_merkle_proof, verify_compact_proof,
+            verify_exclusion_proof, verify_merkle_proof, verify_multi_proof,
+        },
         store::types::{Hashable, Key, Leaf, Node, NodeHash, Root},
     };
     use std::{env, time::Instant};
@@ -101,25 +662,19 @@ pub mod tests {
 
     #[test]
     fn test_merkle_proof() {
-        let mut db = TrieDB {
-            path: env::var("PATH_TO_DB").unwrap_or("database.sqlite".to_string()),
-            cache: None,
-        };
-        db.setup();
-        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 256]);
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 32]);
         leaf_1.hash();
 
-        let mut leaf_2_key = vec![0, 0];
-        for _i in 0..254 {
-            leaf_2_key.push(1);
-        }
+        let mut leaf_2_key = vec![u8::MAX; 32];
+        leaf_2_key[0] = 0b0011_1111;
         let mut leaf_2: Leaf = Leaf::empty(leaf_2_key);
         leaf_2.hash();
         let root: Root = Root::empty();
         let root_node: Node = Node::Root(root);
         let new_root: Root = insert_leaf(&mut db, &mut leaf_1, root_node).unwrap();
         let new_root: Root = insert_leaf(&mut db, &mut leaf_2, Node::Root(new_root)).unwrap();
-        let proof = merkle_proof(&mut db, leaf_2.key, Node::Root(new_root.clone()));
+        let proof = merkle_proof(&mut db, &leaf_2.key, Node::Root(new_root.clone()));
         // verify merkle proof
         let inner_proof = proof.unwrap().nodes;
         assert_eq!(
@@ -133,30 +688,283 @@ pub mod tests {
                 .hash,
             leaf_2.hash
         );
-        verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap();
+        assert!(verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap());
 
-        let proof = merkle_proof(&mut db, leaf_1.key, Node::Root(new_root.clone()));
+        let proof = merkle_proof(&mut db, &leaf_1.key, Node::Root(new_root.clone()));
         let inner_proof = proof.unwrap().nodes;
-        verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap();
+        assert!(verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap());
     }
 
     #[test]
-    fn simulate_insert_flow() {
-        let mut db = TrieDB {
-            path: env::var("PATH_TO_DB").unwrap_or("database.sqlite".to_string()),
-            cache: None,
+    fn test_verify_malformed_proof() {
+        let mut db = InMemoryDB::new();
+        let mut root = Node::Root(Root::empty());
+        let mut keys: Vec<Key> = Vec::new();
+        for leaf in generate_random_leaves(10) {
+            root = Node::Root(insert_leaf(&mut db, &mut leaf.clone(), root).unwrap());
+            keys.push(leaf.key);
+        }
+        let state_root_hash = root.clone().unwrap_as_root().unwrap().hash.unwrap();
+        let inner_proof = merkle_proof(&mut db, &keys[0], root).unwrap().nodes;
+        assert!(verify_merkle_proof(inner_proof.clone(), state_root_hash.clone()).unwrap());
+
+        assert_eq!(
+            verify_merkle_proof(Vec::new(), state_root_hash.clone()),
+            Err(ProofError::EmptyProof)
+        );
+        assert_eq!(
+            verify_merkle_proof(inner_proof[1..].to_vec(), state_root_hash.clone()),
+            Err(ProofError::MissingRoot)
+        );
+        assert_eq!(
+            verify_merkle_proof(
+                inner_proof[..inner_proof.len() - 1].to_vec(),
+                state_root_hash.clone()
+            ),
+            Err(ProofError::MissingLeaf)
+        );
+        let mut leaf_in_the_middle = inner_proof.clone();
+        let leaf_node = inner_proof.last().unwrap().1.clone();
+        leaf_in_the_middle.insert(1, (inner_proof[1].0, leaf_node));
+        assert!(matches!(
+            verify_merkle_proof(leaf_in_the_middle, state_root_hash.clone()),
+            Err(ProofError::UnexpectedLeaf { depth: 1 })
+        ));
+        // a well-formed proof against the wrong root is rejected without an error
+        assert!(!verify_merkle_proof(inner_proof, vec![0u8; 32]).unwrap());
+    }
+
+    #[test]
+    fn test_compact_merkle_proof() {
+        let mut db = InMemoryDB::new();
+        let mut root = Node::Root(Root::empty());
+        let mut leafs: Vec<Leaf> = Vec::new();
+        for leaf in generate_random_leaves(20) {
+            root = Node::Root(insert_leaf(&mut db, &mut leaf.clone(), root).unwrap());
+            leafs.push(leaf);
+        }
+        let state_root_hash = root.clone().unwrap_as_root().unwrap().hash.unwrap();
+        for leaf in leafs {
+            let full_proof = merkle_proof(&mut db, &leaf.key, root.clone()).unwrap();
+            let proof = merkle_proof_compact(&mut db, &leaf.key, root.clone()).unwrap();
+            assert_eq!(proof.leaf, leaf);
+            assert!(
+                bincode::serialize(&proof).unwrap().len()
+                    < bincode::serialize(&full_proof).unwrap().len()
+            );
+            assert!(verify_compact_proof(proof.clone(), state_root_hash.clone()).unwrap());
+
+            let mut forged_proof = proof.clone();
+            forged_proof.leaf.data = Some(vec![0]);
+            assert!(!verify_compact_proof(forged_proof, state_root_hash.clone()).unwrap());
+            let mut forged_proof = proof;
+            forged_proof.directions[0] = !forged_proof.directions[0];
+            assert!(verify_compact_proof(forged_proof, state_root_hash.clone()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let mut db = InMemoryDB::new();
+        let mut root = Node::Root(Root::empty());
+        let mut keys: Vec<Key> = Vec::new();
+        for leaf in generate_random_leaves(50) {
+            root = Node::Root(insert_leaf(&mut db, &mut leaf.clone(), root).unwrap());
+            keys.push(leaf.key);
+        }
+        let state_root_hash = root.clone().unwrap_as_root().unwrap().hash.unwrap();
+        let proof = multi_merkle_proof(&mut db, keys[..25].to_vec(), root.clone()).unwrap();
+        let single_proof_nodes: usize = keys[..25]
+            .iter()
+            .map(|key| {
+                merkle_proof(&mut db, key, root.clone())
+                    .unwrap()
+                    .nodes
+                    .len()
+            })
+            .sum();
+        assert!(proof.nodes.len() < single_proof_nodes);
+        assert!(verify_multi_proof(proof.clone(), state_root_hash.clone()).unwrap());
+
+        // keys that are not covered by the nodes of the proof are rejected
+        let mut forged_proof = proof.clone();
+        forged_proof.keys.push(keys[30].clone());
+        assert!(!matches!(
+            verify_multi_proof(forged_proof, state_root_hash.clone()),
+            Ok(true)
+        ));
+        let mut forged_proof = proof.clone();
+        forged_proof.nodes.remove(1);
+        assert_eq!(
+            verify_multi_proof(forged_proof, state_root_hash.clone()),
+            Err(ProofError::MissingNode { depth: 1 })
+        );
+        // a leaf that no key leads to is not silently accepted
+        let mut forged_proof = proof.clone();
+        let mut forged_leaf: Leaf = Leaf::new(generate_random_key(), Some(vec![1]));
+        forged_leaf.hash();
+        forged_proof.nodes.push(Node::Leaf(forged_leaf));
+        assert_eq!(
+            verify_multi_proof(forged_proof, state_root_hash.clone()),
+            Err(ProofError::UnreachableNode)
+        );
+        assert!(!verify_multi_proof(proof, vec![0u8; 32]).unwrap());
+    }
+
+    #[test]
+    fn test_exclusion_proof() {
+        let mut db = InMemoryDB::new();
+        let mut leaf_1: Leaf = Leaf::empty(vec![0u8; 32]);
+        leaf_1.hash();
+        let mut leaf_2_key = vec![u8::MAX; 32];
+        leaf_2_key[0] = 0b0011_1111;
+        let mut leaf_2: Leaf = Leaf::empty(leaf_2_key);
+        leaf_2.hash();
+        let new_root: Root = insert_leaf(&mut db, &mut leaf_1, Node::Root(Root::empty())).unwrap();
+        let new_root: Root = insert_leaf(&mut db, &mut leaf_2, Node::Root(new_root)).unwrap();
+        let state_root_hash = new_root.hash.clone().unwrap();
+
+        // the path of this key ends at leaf_1
+        let mut missing_key = vec![0u8; 31];
+        missing_key.push(1);
+        let proof = exclusion_proof(&mut db, &missing_key, Node::Root(new_root.clone())).unwrap();
+        assert_eq!(proof.neq_idx, Some(255));
+        assert_eq!(proof.conflicting_leaf, Some(leaf_1.clone()));
+        assert!(verify_exclusion_proof(proof.clone(), state_root_hash.clone()).unwrap());
+
+        // the proof can't be reused for a different key or root
+        let mut forged_proof = proof.clone();
+        forged_proof.key = leaf_1.key.clone();
+        assert_eq!(
+            verify_exclusion_proof(forged_proof, state_root_hash.clone()),
+            Err(ProofError::ConflictingLeaf)
+        );
+        assert!(!verify_exclusion_proof(proof, vec![0u8; 32]).unwrap());
+
+        // the right side of the root is empty
+        let proof = exclusion_proof(&mut db, &[u8::MAX; 32], Node::Root(new_root.clone())).unwrap();
+        assert!(proof.conflicting_leaf.is_none());
+        assert!(verify_exclusion_proof(proof, state_root_hash).unwrap());
+
+        // keys that are in the trie can't be excluded
+        assert!(exclusion_proof(&mut db, &leaf_2.key, Node::Root(new_root)).is_err());
+    }
+
+    #[test]
+    fn test_range_proof() {
+        use crate::{
+            error::TrieError,
+            insert_batch,
+            iter::range,
+            merkle::{range_proof, verify_range_proof},
         };
-        db.setup();
+
+        // an empty trie has no leaves in any range
+        let mut db = InMemoryDB::new();
+        let mut empty_root = Root::empty();
+        empty_root.hash();
+        let proof = range_proof(
+            &mut db,
+            &[0u8; 32],
+            &[1u8; 32],
+            Node::Root(empty_root.clone()),
+        )
+        .unwrap();
+        assert!(verify_range_proof(proof, &[], empty_root.hash.clone().unwrap()).unwrap());
+
+        let leaves: Vec<Leaf> = generate_random_leaves(100);
+        let root = insert_batch(&mut db, leaves, Node::Root(Root::empty())).unwrap();
+        let state_root_hash = root.hash.clone().unwrap();
+        let root = Node::Root(root);
+        let keys: Vec<Key> = range(&mut db, &[0u8; 32], &[u8::MAX; 32], root.clone())
+            .unwrap()
+            .into_iter()
+            .map(|leaf| leaf.key)
+            .collect();
+        assert_eq!(keys.len(), 100);
+
+        let mut ranges: Vec<(Key, Key)> = vec![
+            (vec![0u8; 32], vec![u8::MAX; 32]),
+            (vec![0u8; 32], keys[0].clone()),
+            (keys[10].clone(), keys[20].clone()),
+            (keys[99].clone(), vec![u8::MAX; 32]),
+        ];
+        // ranges between two adjacent keys are empty
+        let mut after_key = keys[50].clone();
+        after_key[31] = after_key[31].wrapping_add(1);
+        ranges.push((after_key, keys[51].clone()));
+        for _ in 0..20 {
+            let mut bounds = [generate_random_key(), generate_random_key()];
+            bounds.sort();
+            ranges.push((bounds[0].clone(), bounds[1].clone()));
+        }
+        for (start, end) in ranges {
+            let in_range = range(&mut db, &start, &end, root.clone()).unwrap();
+            let expected: Vec<&Key> = keys.iter().filter(|k| **k >= start && **k < end).collect();
+            assert!(in_range.iter().map(|leaf| &leaf.key).eq(expected));
+            let proof = range_proof(&mut db, &start, &end, root.clone()).unwrap();
+            assert!(verify_range_proof(proof.clone(), &in_range, state_root_hash.clone()).unwrap());
+            assert!(!verify_range_proof(proof.clone(), &in_range, vec![0u8; 32]).unwrap());
+
+            // leaving out a leaf of the range is detected
+            if !in_range.is_empty() {
+                let omitted = &in_range[in_range.len() / 2];
+                assert!(!verify_range_proof(
+                    proof.clone(),
+                    &in_range[1..],
+                    state_root_hash.clone()
+                )
+                .unwrap());
+                let mut forged_proof = proof.clone();
+                forged_proof
+                    .nodes
+                    .retain(|node| !matches!(node, Node::Leaf(leaf) if leaf == omitted));
+                let mut forged_leaves = in_range.clone();
+                forged_leaves.retain(|leaf| leaf != omitted);
+                assert_eq!(
+                    verify_range_proof(forged_proof, &forged_leaves, state_root_hash.clone()),
+                    Err(ProofError::IncompleteRange)
+                );
+            }
+        }
+
+        // the leaves are compared by content, whether their hash is cached or not
+        let (start, end) = (keys[10].clone(), keys[20].clone());
+        let proof = range_proof(&mut db, &start, &end, root.clone()).unwrap();
+        let mut unhashed = range(&mut db, &start, &end, root.clone()).unwrap();
+        for leaf in unhashed.iter_mut() {
+            leaf.hash = None;
+        }
+        assert!(verify_range_proof(proof.clone(), &unhashed, state_root_hash.clone()).unwrap());
+        unhashed[0].data = Some(vec![1]);
+        assert!(!verify_range_proof(proof, &unhashed, state_root_hash).unwrap());
+
+        // an empty or reversed range is rejected when it is read and when it is proven
+        for (start, end) in [(&keys[1], &keys[0]), (&keys[0], &keys[0])] {
+            assert!(matches!(
+                range(&mut db, start, end, root.clone()),
+                Err(TrieError::InvalidRange { .. })
+            ));
+            assert!(matches!(
+                range_proof(&mut db, start, end, root.clone()),
+                Err(TrieError::InvalidRange { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn simulate_insert_flow() {
+        let mut db = InMemoryDB::new();
         let root: Root = Root::empty();
         let root_node: Node = Node::Root(root);
         let mut current_root = root_node.clone();
-        let Message_count: u32 = env::var("STRESS_TEST_MESSAGE_COUNT")
+        let message_count: u32 = env::var("STRESS_TEST_MESSAGE_COUNT")
             .unwrap_or_else(|_| "1000".to_string())
             .parse::<u32>()
             .expect("Invalid argument STRESS_TEST_MESSAGE_COUNT");
-        let progress_bar: ProgressBar = ProgressBar::new(Message_count as u64);
+        let progress_bar: ProgressBar = ProgressBar::new(message_count as u64);
         let mut leafs: Vec<Leaf> = Vec::new();
-        for _ in 0..Message_count {
+        for _ in 0..message_count {
             let leaf_key: Key = generate_random_key();
             let mut leaf: Leaf = Leaf::empty(leaf_key.clone());
             leaf.data = Some(generate_random_data());
@@ -168,21 +976,21 @@ pub mod tests {
             leaf.hash();
             let new_root: Root =
                 insert_leaf(&mut db, &mut leaf.clone(), current_root.clone()).unwrap();
-            let proof = merkle_proof(&mut db, leaf.key.clone(), Node::Root(new_root.clone()));
+            let proof = merkle_proof(&mut db, &leaf.key, Node::Root(new_root.clone()));
             let inner_proof = proof.unwrap().nodes;
-            verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap();
+            assert!(verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap());
 
             #[cfg(feature = "stress-test")]
             for key in leaf_keys.clone() {
-                let proof = merkle_proof(&mut db, key, Node::Root(new_root.clone()));
-                let mut inner_proof = proof.unwrap().nodes;
-                verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap());
+                let proof = merkle_proof(&mut db, &key, Node::Root(new_root.clone()));
+                let inner_proof = proof.unwrap().nodes;
+                assert!(verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap());
             }
             #[cfg(not(feature = "stress-test"))]
             {
-                let proof = merkle_proof(&mut db, leaf.key.clone(), Node::Root(new_root.clone()));
+                let proof = merkle_proof(&mut db, &leaf.key, Node::Root(new_root.clone()));
                 let inner_proof = proof.unwrap().nodes;
-                verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap();
+                assert!(verify_merkle_proof(inner_proof, new_root.hash.clone().unwrap()).unwrap());
             }
             leaf_keys.push(leaf.key.clone());
             current_root = Node::Root(new_root.clone());
@@ -191,7 +999,7 @@ pub mod tests {
         progress_bar.finish_with_message("Done checking merkle proofs!");
         println!(
             "[{}x Merkle Proof] Elapsed Time: {} s",
-            Message_count.to_string().yellow(),
+            message_count.to_string().yellow(),
             &start_time.elapsed().as_secs().to_string().blue()
         );
     }
@@ -200,12 +1008,20 @@ pub mod tests {
     use rand::Rng;
     pub fn generate_random_key() -> Key {
         let mut rng = rand::thread_rng();
-        (0..256)
-            .map(|_| if rng.gen_bool(0.5) { 1 } else { 0 })
-            .collect()
+        (0..32).map(|_| rng.gen()).collect()
     }
     pub fn generate_random_data() -> Key {
         let mut rng = rand::thread_rng();
         (0..256).map(|_| rng.gen_range(0..255)).collect()
     }
+    // hashed leaves with random keys and data
+    pub fn generate_random_leaves(count: usize) -> Vec<Leaf> {
+        (0..count)
+            .map(|_| {
+                let mut leaf = Leaf::new(generate_random_key(), Some(generate_random_data()));
+                leaf.hash();
+                leaf
+            })
+            .collect()
+    }
 }
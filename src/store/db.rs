@@ -1,60 +1,472 @@
-use crate::store::types::Node;
+use crate::{
+    error::Result,
+    store::types::{Node, NodeHash, RootHash},
+};
+use std::collections::HashMap;
+
 pub trait Database {
-    fn insert(&mut self, key: &[u8], node: Node);
-    fn get(&mut self, key: &[u8]) -> Option<&mut Node>;
+    fn insert(&mut self, key: &[u8], node: Node) -> Result<()>;
+    fn get(&mut self, key: &[u8]) -> Result<Option<Node>>;
+    // store all nodes at once, either every node is written or none of them
+    fn write_batch(&mut self, nodes: Vec<(NodeHash, Node)>) -> Result<()>;
+}
+
+// records which root belongs to which version or block height, so that the head
+// state can be found again after a restart
+pub trait RootRegistry {
+    // a root that is recorded at an existing height replaces the previous one
+    fn record_root(&mut self, height: u64, root_hash: &RootHash) -> Result<()>;
+    fn root_at(&mut self, height: u64) -> Result<Option<RootHash>>;
+    // the root at the highest recorded height
+    fn latest(&mut self) -> Result<Option<(u64, RootHash)>>;
+    // all recorded roots in ascending order of height
+    fn roots(&mut self) -> Result<Vec<(u64, RootHash)>>;
+    fn is_recorded(&mut self, root_hash: &RootHash) -> Result<bool>;
+    // forget every root recorded below a height, their nodes are left for store::prune
+    fn remove_roots_below(&mut self, height: u64) -> Result<()>;
+}
+
+// backends whose nodes can be listed and deleted, see store::prune
+pub trait Prunable {
+    fn node_hashes(&mut self) -> Result<Vec<NodeHash>>;
+    // delete all nodes at once, either every node is deleted or none of them
+    fn delete_batch(&mut self, keys: Vec<NodeHash>) -> Result<()>;
+}
+
+// backends that count how many stored nodes link to each node. A node gains a reference
+// when a new parent is written and loses it when that parent is deleted, so the nodes of
+// a released root can be deleted without marking every live root first.
+// Nodes are content addressed, a node that is already stored keeps its count when it is
+// written again. The children of a batch are linked once every node of the batch is
+// stored. Deleting a node unlinks its children, release goes on with the children that
+// lost their last parent with it.
+// Nodes that were written before the backend counted references have no count and are
+// never deleted by a release, store::prune still removes them.
+pub trait RefCounted {
+    fn ref_count(&mut self, key: &[u8]) -> Result<Option<u64>>;
+    // delete a root that is no longer needed together with every node that was only
+    // linked by it and return how many nodes were deleted, a node that still has a
+    // parent is left untouched
+    fn release(&mut self, root_hash: &RootHash) -> Result<usize>;
+}
+
+// Buffers all writes in memory until they are committed to the underlying
+// database in a single write_batch, reads see the uncommitted writes
+pub struct WriteBatch<'a> {
+    db: &'a mut dyn Database,
+    nodes: HashMap<NodeHash, Node>,
+}
+
+impl<'a> WriteBatch<'a> {
+    pub fn new(db: &'a mut dyn Database) -> Self {
+        Self {
+            db,
+            nodes: HashMap::new(),
+        }
+    }
+    pub fn commit(self) -> Result<()> {
+        if self.nodes.is_empty() {
+            return Ok(());
+        }
+        self.db.write_batch(self.nodes.into_iter().collect())
+    }
+}
+
+impl Database for WriteBatch<'_> {
+    fn insert(&mut self, key: &[u8], node: Node) -> Result<()> {
+        self.nodes.insert(key.to_vec(), node);
+        Ok(())
+    }
+    fn get(&mut self, key: &[u8]) -> Result<Option<Node>> {
+        match self.nodes.get(key) {
+            Some(node) => Ok(Some(node.clone())),
+            None => self.db.get(key),
+        }
+    }
+    fn write_batch(&mut self, nodes: Vec<(NodeHash, Node)>) -> Result<()> {
+        self.nodes.extend(nodes);
+        Ok(())
+    }
+}
+
+pub mod memory {
+    use super::{Database, Prunable, RefCounted, RootRegistry};
+    use crate::{
+        error::Result,
+        store::types::{Node, NodeHash, RootHash},
+    };
+    use std::collections::{BTreeMap, HashMap};
+
+    // HashMap backend for tests and short-lived tries that don't need to touch the disk
+    #[derive(Clone, Debug, Default)]
+    pub struct InMemoryDB {
+        nodes: HashMap<NodeHash, Node>,
+        refs: HashMap<NodeHash, u64>,
+        roots: BTreeMap<u64, RootHash>,
+    }
+    impl InMemoryDB {
+        pub fn new() -> Self {
+            Self::default()
+        }
+        pub fn len(&self) -> usize {
+            self.nodes.len()
+        }
+        pub fn is_empty(&self) -> bool {
+            self.nodes.is_empty()
+        }
+        fn unlink(&mut self, key: &[u8]) -> Vec<NodeHash> {
+            self.refs.remove(key);
+            let Some(node) = self.nodes.remove(key) else {
+                return Vec::new();
+            };
+            let mut unlinked: Vec<NodeHash> = Vec::new();
+            for child in node.children() {
+                if let Some(count) = self.refs.get_mut(&child) {
+                    *count = count.saturating_sub(1);
+                    if *count == 0 {
+                        unlinked.push(child);
+                    }
+                }
+            }
+            unlinked
+        }
+    }
+    impl Database for InMemoryDB {
+        fn insert(&mut self, key: &[u8], node: Node) -> Result<()> {
+            self.write_batch(vec![(key.to_vec(), node)])
+        }
+        fn get(&mut self, key: &[u8]) -> Result<Option<Node>> {
+            Ok(self.nodes.get(key).cloned())
+        }
+        fn write_batch(&mut self, nodes: Vec<(NodeHash, Node)>) -> Result<()> {
+            let mut children: Vec<NodeHash> = Vec::new();
+            for (key, node) in nodes {
+                if self.nodes.contains_key(&key) {
+                    continue;
+                }
+                children.extend(node.children());
+                self.refs.insert(key.clone(), 0);
+                self.nodes.insert(key, node);
+            }
+            for child in children {
+                if let Some(count) = self.refs.get_mut(&child) {
+                    *count += 1;
+                }
+            }
+            Ok(())
+        }
+    }
+    impl RootRegistry for InMemoryDB {
+        fn record_root(&mut self, height: u64, root_hash: &RootHash) -> Result<()> {
+            self.roots.insert(height, root_hash.clone());
+            Ok(())
+        }
+        fn root_at(&mut self, height: u64) -> Result<Option<RootHash>> {
+            Ok(self.roots.get(&height).cloned())
+        }
+        fn latest(&mut self) -> Result<Option<(u64, RootHash)>> {
+            Ok(self
+                .roots
+                .last_key_value()
+                .map(|(height, root_hash)| (*height, root_hash.clone())))
+        }
+        fn roots(&mut self) -> Result<Vec<(u64, RootHash)>> {
+            Ok(self
+                .roots
+                .iter()
+                .map(|(height, root_hash)| (*height, root_hash.clone()))
+                .collect())
+        }
+        fn is_recorded(&mut self, root_hash: &RootHash) -> Result<bool> {
+            Ok(self.roots.values().any(|recorded| recorded == root_hash))
+        }
+        fn remove_roots_below(&mut self, height: u64) -> Result<()> {
+            self.roots = self.roots.split_off(&height);
+            Ok(())
+        }
+    }
+    impl Prunable for InMemoryDB {
+        fn node_hashes(&mut self) -> Result<Vec<NodeHash>> {
+            Ok(self.nodes.keys().cloned().collect())
+        }
+        fn delete_batch(&mut self, keys: Vec<NodeHash>) -> Result<()> {
+            for key in keys {
+                self.unlink(&key);
+            }
+            Ok(())
+        }
+    }
+    impl RefCounted for InMemoryDB {
+        fn ref_count(&mut self, key: &[u8]) -> Result<Option<u64>> {
+            Ok(self.refs.get(key).copied())
+        }
+        fn release(&mut self, root_hash: &RootHash) -> Result<usize> {
+            if self.refs.get(root_hash) != Some(&0) {
+                return Ok(0);
+            }
+            let mut deleted: usize = 0;
+            let mut pending: Vec<NodeHash> = vec![root_hash.clone()];
+            while let Some(key) = pending.pop() {
+                pending.extend(self.unlink(&key));
+                deleted += 1;
+            }
+            Ok(deleted)
+        }
+    }
 }
 
 pub mod sql {
     extern crate rusqlite;
-    use super::Database;
-    use crate::store::types::Node;
-    use rusqlite::{params, Connection};
+    use super::{Database, Prunable, RefCounted, RootRegistry};
+    use crate::{
+        error::Result,
+        store::types::{Node, NodeHash, RootHash},
+    };
+    use rusqlite::{params, Connection, OptionalExtension};
 
+    const INSERT_NODE: &str = "INSERT OR IGNORE INTO nodes (key, node) VALUES (?1, ?2)";
+    const SELECT_NODE: &str = "SELECT node FROM nodes WHERE key = ?1 LIMIT 1";
+    const INSERT_ROOT: &str = "INSERT OR REPLACE INTO roots (height, root) VALUES (?1, ?2)";
+    const SELECT_ROOT: &str = "SELECT root FROM roots WHERE height = ?1";
+    const SELECT_LATEST_ROOT: &str = "SELECT height, root FROM roots ORDER BY height DESC LIMIT 1";
+    const SELECT_ROOTS: &str = "SELECT height, root FROM roots ORDER BY height ASC";
+    const SELECT_RECORDED: &str = "SELECT 1 FROM roots WHERE root = ?1 LIMIT 1";
+    const DELETE_ROOTS_BELOW: &str = "DELETE FROM roots WHERE height < ?1";
+    const SELECT_NODE_KEYS: &str = "SELECT key FROM nodes";
+    const DELETE_NODE: &str = "DELETE FROM nodes WHERE key = ?1";
+    const INSERT_REF: &str = "INSERT OR REPLACE INTO refs (key, count) VALUES (?1, 0)";
+    const SELECT_REF: &str = "SELECT count FROM refs WHERE key = ?1";
+    const INCREMENT_REF: &str = "UPDATE refs SET count = count + 1 WHERE key = ?1";
+    const DECREMENT_REF: &str = "UPDATE refs SET count = count - 1 WHERE key = ?1 AND count > 0";
+    const DELETE_REF: &str = "DELETE FROM refs WHERE key = ?1";
+
+    // SQLite backend that keeps a single connection open for its whole lifetime,
+    // statements are prepared once and then served from the connection's cache
     pub struct TrieDB {
-        pub path: String,
-        pub cache: Option<Node>,
+        conn: Connection,
     }
     impl TrieDB {
-        pub fn setup(&self) {
-            let conn = Connection::open(&self.path).expect("Unhandled Error: SQL Connection");
-            conn.execute(
+        pub fn new(path: &str) -> Result<Self> {
+            let conn = Connection::open(path)?;
+            // readers don't block the writer of the next batch
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            let db = Self { conn };
+            db.setup()?;
+            Ok(db)
+        }
+        pub fn setup(&self) -> Result<()> {
+            self.conn.execute(
                 "CREATE TABLE IF NOT EXISTS nodes (
                           key    BLOB PRIMARY KEY,
                           node   BLOB NOT NULL
                           )",
                 [],
-            )
-            .expect("Unhandled Error: SQL Insert");
+            )?;
+            self.conn.execute(
+                "CREATE TABLE IF NOT EXISTS roots (
+                          height INTEGER PRIMARY KEY,
+                          root   BLOB NOT NULL
+                          )",
+                [],
+            )?;
+            self.conn.execute(
+                "CREATE INDEX IF NOT EXISTS roots_by_root ON roots (root)",
+                [],
+            )?;
+            // nodes of databases that were created before this table have no count
+            self.conn.execute(
+                "CREATE TABLE IF NOT EXISTS refs (
+                          key    BLOB PRIMARY KEY,
+                          count  INTEGER NOT NULL
+                          )",
+                [],
+            )?;
+            Ok(())
+        }
+    }
+
+    // store the nodes that are not in the database yet and link their children
+    fn link_nodes(conn: &Connection, nodes: Vec<(NodeHash, Node)>) -> Result<()> {
+        let mut insert_node = conn.prepare_cached(INSERT_NODE)?;
+        let mut insert_ref = conn.prepare_cached(INSERT_REF)?;
+        let mut children: Vec<NodeHash> = Vec::new();
+        for (key, node) in nodes {
+            if insert_node.execute(params![key, bincode::serialize(&node)?])? == 1 {
+                insert_ref.execute([&key])?;
+                children.extend(node.children());
+            }
+        }
+        let mut increment_ref = conn.prepare_cached(INCREMENT_REF)?;
+        for child in children {
+            increment_ref.execute([child])?;
         }
+        Ok(())
+    }
+
+    fn unlink_node(conn: &Connection, key: &[u8]) -> Result<Vec<NodeHash>> {
+        let node_serialized: Option<Vec<u8>> = conn
+            .prepare_cached(SELECT_NODE)?
+            .query_row([key], |row| row.get(0))
+            .optional()?;
+        conn.prepare_cached(DELETE_REF)?.execute([key])?;
+        let Some(node_serialized) = node_serialized else {
+            return Ok(Vec::new());
+        };
+        let node: Node = bincode::deserialize(&node_serialized)?;
+        conn.prepare_cached(DELETE_NODE)?.execute([key])?;
+        let mut unlinked: Vec<NodeHash> = Vec::new();
+        for child in node.children() {
+            conn.prepare_cached(DECREMENT_REF)?.execute([&child])?;
+            if ref_count(conn, &child)? == Some(0) {
+                unlinked.push(child);
+            }
+        }
+        Ok(unlinked)
+    }
+
+    fn ref_count(conn: &Connection, key: &[u8]) -> Result<Option<u64>> {
+        let mut stmt = conn.prepare_cached(SELECT_REF)?;
+        Ok(stmt.query_row([key], |row| row.get(0)).optional()?)
     }
     impl Database for TrieDB {
-        fn insert(&mut self, key: &[u8], node: Node) {
-            let conn = Connection::open(&self.path).expect("Unhandled Error: SQL Connection");
-            conn.execute(
-                "INSERT OR REPLACE INTO nodes (key, node) VALUES (?1, ?2)",
-                params![key, bincode::serialize(&node).unwrap()],
-            )
-            .expect("Unhandled Error: SQL Insert");
-        }
-        fn get(&mut self, key: &[u8]) -> Option<&mut Node> {
-            let conn = Connection::open(&self.path).unwrap();
-            let mut stmt = conn
-                .prepare("SELECT node FROM nodes WHERE key = ?1 LIMIT 1")
-                .expect("Unhandled Error: SQL Connection");
-
-            let node_serialized: Option<Vec<u8>> = stmt
-                .query_row([&key], |row| {
-                    let node_serialized: Vec<u8> = row.get(0).unwrap();
-                    Ok(Some(node_serialized))
-                })
-                .unwrap_or(None);
-
-            if let Some(node_serialized) = node_serialized {
-                let node: Node = bincode::deserialize(&node_serialized).unwrap();
-                self.cache = Some(node);
-                self.cache.as_mut()
-            } else {
-                None
+        fn insert(&mut self, key: &[u8], node: Node) -> Result<()> {
+            self.write_batch(vec![(key.to_vec(), node)])
+        }
+        fn get(&mut self, key: &[u8]) -> Result<Option<Node>> {
+            let mut stmt = self.conn.prepare_cached(SELECT_NODE)?;
+            let node_serialized: Option<Vec<u8>> =
+                stmt.query_row([&key], |row| row.get(0)).optional()?;
+
+            match node_serialized {
+                Some(node_serialized) => Ok(Some(bincode::deserialize(&node_serialized)?)),
+                None => Ok(None),
+            }
+        }
+        fn write_batch(&mut self, nodes: Vec<(NodeHash, Node)>) -> Result<()> {
+            let tx = self.conn.transaction()?;
+            link_nodes(&tx, nodes)?;
+            // dropping the transaction without a commit rolls back every write
+            tx.commit()?;
+            Ok(())
+        }
+    }
+    impl RootRegistry for TrieDB {
+        fn record_root(&mut self, height: u64, root_hash: &RootHash) -> Result<()> {
+            let mut stmt = self.conn.prepare_cached(INSERT_ROOT)?;
+            stmt.execute(params![height, root_hash])?;
+            Ok(())
+        }
+        fn root_at(&mut self, height: u64) -> Result<Option<RootHash>> {
+            let mut stmt = self.conn.prepare_cached(SELECT_ROOT)?;
+            Ok(stmt.query_row([height], |row| row.get(0)).optional()?)
+        }
+        fn latest(&mut self) -> Result<Option<(u64, RootHash)>> {
+            let mut stmt = self.conn.prepare_cached(SELECT_LATEST_ROOT)?;
+            Ok(stmt
+                .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?)
+        }
+        fn roots(&mut self) -> Result<Vec<(u64, RootHash)>> {
+            let mut stmt = self.conn.prepare_cached(SELECT_ROOTS)?;
+            let roots = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(u64, RootHash)>>>()?;
+            Ok(roots)
+        }
+        fn is_recorded(&mut self, root_hash: &RootHash) -> Result<bool> {
+            let mut stmt = self.conn.prepare_cached(SELECT_RECORDED)?;
+            Ok(stmt.exists([root_hash])?)
+        }
+        fn remove_roots_below(&mut self, height: u64) -> Result<()> {
+            let mut stmt = self.conn.prepare_cached(DELETE_ROOTS_BELOW)?;
+            stmt.execute([height])?;
+            Ok(())
+        }
+    }
+    impl Prunable for TrieDB {
+        fn node_hashes(&mut self) -> Result<Vec<NodeHash>> {
+            let mut stmt = self.conn.prepare_cached(SELECT_NODE_KEYS)?;
+            let keys = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<NodeHash>>>()?;
+            Ok(keys)
+        }
+        fn delete_batch(&mut self, keys: Vec<NodeHash>) -> Result<()> {
+            let tx = self.conn.transaction()?;
+            for key in keys {
+                unlink_node(&tx, &key)?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+    }
+    impl RefCounted for TrieDB {
+        fn ref_count(&mut self, key: &[u8]) -> Result<Option<u64>> {
+            ref_count(&self.conn, key)
+        }
+        fn release(&mut self, root_hash: &RootHash) -> Result<usize> {
+            // all counts change in one transaction, an interrupted release deletes nothing
+            let tx = self.conn.transaction()?;
+            if ref_count(&tx, root_hash)? != Some(0) {
+                return Ok(0);
+            }
+            let mut deleted: usize = 0;
+            let mut pending: Vec<NodeHash> = vec![root_hash.clone()];
+            while let Some(key) = pending.pop() {
+                pending.extend(unlink_node(&tx, &key)?);
+                deleted += 1;
+            }
+            tx.commit()?;
+            Ok(deleted)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::sql::TrieDB;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // a TrieDB in a file of its own, the file is removed when the guard is dropped so that
+    // tests running in parallel never share a database, even if one of them panics
+    pub struct TempDB {
+        path: PathBuf,
+    }
+    impl TempDB {
+        pub fn new(name: &str) -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let file_name = format!(
+                "{}-{}-{}.sqlite",
+                name,
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            );
+            Self {
+                path: std::env::temp_dir().join(file_name),
+            }
+        }
+        pub fn path(&self) -> &str {
+            self.path.to_str().unwrap()
+        }
+        // every connection opens the same file
+        pub fn open(&self) -> TrieDB {
+            TrieDB::new(self.path()).unwrap()
+        }
+        // the database at PATH_TO_DB instead if it is set, it is kept after the test
+        pub fn open_or_env(&self) -> TrieDB {
+            match std::env::var("PATH_TO_DB") {
+                Ok(path) => TrieDB::new(&path).unwrap(),
+                Err(_) => self.open(),
+            }
+        }
+    }
+    impl Drop for TempDB {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.path.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
             }
         }
     }
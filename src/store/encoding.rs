@@ -0,0 +1,183 @@
+use super::types::{Branch, Leaf, Root};
+
+// The canonical encoding of a node is the input of its hash, it does not depend on the
+// serialization that is used for storage and can be reimplemented by external verifiers:
+//
+//   leaf   = 0x00 || bytes(key) || option(data) || option(preimage)
+//   branch = 0x01 || split index (1 byte) || option(left) || option(right)
+//   root   = 0x02 || option(left) || option(right)
+//
+//   bytes(b)        = length of b as u32 big-endian || b
+//   option(None)    = 0x00
+//   option(Some(b)) = 0x01 || bytes(b)
+//
+// The hash field of a node is never encoded.
+pub const LEAF_TAG: u8 = 0x00;
+pub const BRANCH_TAG: u8 = 0x01;
+pub const ROOT_TAG: u8 = 0x02;
+
+pub fn encode_leaf(leaf: &Leaf) -> Vec<u8> {
+    let mut encoding = vec![LEAF_TAG];
+    write_bytes(&mut encoding, &leaf.key);
+    write_option(&mut encoding, leaf.data.as_deref());
+    write_option(&mut encoding, leaf.preimage.as_deref());
+    encoding
+}
+
+pub fn encode_branch(branch: &Branch) -> Vec<u8> {
+    let mut encoding = vec![BRANCH_TAG, branch.split_idx()];
+    write_option(&mut encoding, branch.left.as_deref());
+    write_option(&mut encoding, branch.right.as_deref());
+    encoding
+}
+
+pub fn encode_root(root: &Root) -> Vec<u8> {
+    let mut encoding = vec![ROOT_TAG];
+    write_option(&mut encoding, root.left.as_deref());
+    write_option(&mut encoding, root.right.as_deref());
+    encoding
+}
+
+fn write_bytes(encoding: &mut Vec<u8>, bytes: &[u8]) {
+    encoding.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    encoding.extend_from_slice(bytes);
+}
+
+fn write_option(encoding: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            encoding.push(0x01);
+            write_bytes(encoding, bytes);
+        }
+        None => encoding.push(0x00),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::insert_batch;
+    use crate::store::db::memory::InMemoryDB;
+    use crate::store::hasher::Sha256Hasher;
+    use crate::store::types::{Hashable, Leaf, Node, Root};
+
+    #[test]
+    fn test_node_encoding() {
+        use super::{encode_branch, encode_leaf, encode_root};
+        use crate::store::migrate::rebuild_with;
+        use crate::store::types::Branch;
+
+        let mut leaf_1: Leaf = Leaf::new([0x11; 32], Some(vec![1, 2, 3]));
+        leaf_1.hash();
+        assert_eq!(
+            hex::encode(encode_leaf(&leaf_1)),
+            "000000002011111111111111111111111111111111111111111111111111111111111111110100000003010203\
+             00"
+        );
+        assert_eq!(
+            hex::encode(leaf_1.hash.as_ref().unwrap()),
+            "1cd242df9caa7f1290c16d080141281dc61fec340e3b1c98e866015efcbf8db3"
+        );
+        let mut leaf_2: Leaf = Leaf::new([0x22; 32], Some(Vec::new()));
+        leaf_2.preimage = Some(b"alice".to_vec());
+        leaf_2.hash();
+        assert_eq!(
+            hex::encode(encode_leaf(&leaf_2)),
+            "000000002022222222222222222222222222222222222222222222222222222222222222220100000000\
+             0100000005616c696365"
+        );
+        assert_eq!(
+            hex::encode(leaf_2.hash.as_ref().unwrap()),
+            "d74cfab3e20071e35c813c9a61a949808a04e30ce2a3d2478f2bfb0650f64bea"
+        );
+        let mut branch = Branch::new(5, leaf_1.hash.clone(), leaf_2.hash.clone());
+        branch.hash();
+        assert_eq!(
+            hex::encode(encode_branch(&branch)),
+            "01050100000020\
+             1cd242df9caa7f1290c16d080141281dc61fec340e3b1c98e866015efcbf8db3\
+             0100000020\
+             d74cfab3e20071e35c813c9a61a949808a04e30ce2a3d2478f2bfb0650f64bea"
+        );
+        assert_eq!(
+            hex::encode(branch.hash.as_ref().unwrap()),
+            "548cdfb2d0f102e92d0c69ab09c91570bfa4ff88db0b08acee6a847f9eb400b6"
+        );
+        let mut root = Root::empty();
+        root.left = branch.hash.clone();
+        root.hash();
+        assert_eq!(
+            hex::encode(encode_root(&root)),
+            "020100000020548cdfb2d0f102e92d0c69ab09c91570bfa4ff88db0b08acee6a847f9eb400b600"
+        );
+        assert_eq!(
+            hex::encode(root.hash.as_ref().unwrap()),
+            "a89e86b238cadf9dba80537a2f2af056b55b88380322f5925c6421499233949a"
+        );
+
+        // the stored hash of a node is not part of its encoding
+        let mut hashed_root = root.clone();
+        hashed_root.hash = Some(vec![0; 32]);
+        assert_eq!(encode_root(&hashed_root), encode_root(&root));
+
+        // a trie that was hashed differently is rebuilt with the canonical encoding
+        let expected_root = insert_batch(
+            &mut InMemoryDB::new(),
+            vec![leaf_1.clone(), leaf_2.clone()],
+            Node::Root(Root::empty()),
+        )
+        .unwrap();
+        let mut db = InMemoryDB::new();
+        leaf_1.hash = Some(vec![1; 32]);
+        leaf_2.hash = Some(vec![2; 32]);
+        leaf_1.store(&mut db).unwrap();
+        leaf_2.store(&mut db).unwrap();
+        let mut old_branch = Branch::new(5, leaf_1.hash.clone(), leaf_2.hash.clone());
+        old_branch.hash = Some(vec![3; 32]);
+        old_branch.store(&mut db).unwrap();
+        let mut old_root = Root::empty();
+        old_root.left = old_branch.hash.clone();
+        old_root.hash = Some(vec![4; 32]);
+        old_root.store(&mut db).unwrap();
+        let new_root =
+            rebuild_with(&mut db, &vec![4; 32], &mut InMemoryDB::new(), &Sha256Hasher).unwrap();
+        assert_eq!(new_root.hash, expected_root.hash);
+    }
+
+    #[test]
+    fn test_branch_split_index() {
+        use crate::store::types::{Branch, NodeHash};
+        use serde::Serialize;
+
+        // the layout a branch is stored in, its split index is a key of one byte
+        #[derive(Serialize)]
+        enum StoredNode {
+            #[allow(dead_code)]
+            Root(Root),
+            Branch {
+                key: Vec<u8>,
+                hash: Option<NodeHash>,
+                left: Option<NodeHash>,
+                right: Option<NodeHash>,
+            },
+        }
+        let stored = |key: Vec<u8>| {
+            bincode::serialize(&StoredNode::Branch {
+                key,
+                hash: None,
+                left: Some(vec![1; 32]),
+                right: Some(vec![2; 32]),
+            })
+            .unwrap()
+        };
+        let branch = Node::Branch(Branch::new(5, Some(vec![1; 32]), Some(vec![2; 32])));
+        assert_eq!(bincode::serialize(&branch).unwrap(), stored(vec![5]));
+        let Node::Branch(branch) = bincode::deserialize(&stored(vec![5])).unwrap() else {
+            panic!("a branch is deserialized as a branch");
+        };
+        assert_eq!(branch.split_idx(), 5);
+        // [] would encode like [0] and [5, 9] like [5], neither can be deserialized
+        for key in [vec![], vec![5, 9]] {
+            assert!(bincode::deserialize::<Node>(&stored(key)).is_err());
+        }
+    }
+}
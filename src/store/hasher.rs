@@ -0,0 +1,143 @@
+use super::{
+    encoding::{encode_branch, encode_leaf, encode_root},
+    types::{default_hash, Branch, Leaf, NodeHash, Root},
+};
+
+// the hash function that is applied to the canonical encoding of every node,
+// proofs must be verified with the same hasher that built the trie
+pub trait Hasher {
+    fn digest(&self, data: &[u8]) -> NodeHash;
+    // hashers that need their own node encoding override these
+    fn hash_root(&self, root: &Root) -> NodeHash {
+        self.digest(&encode_root(root))
+    }
+    fn hash_branch(&self, branch: &Branch) -> NodeHash {
+        self.digest(&encode_branch(branch))
+    }
+    fn hash_leaf(&self, leaf: &Leaf) -> NodeHash {
+        self.digest(&encode_leaf(leaf))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn digest(&self, data: &[u8]) -> NodeHash {
+        default_hash(data)
+    }
+}
+
+#[cfg(feature = "blake3")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3Hasher;
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3Hasher {
+    fn digest(&self, data: &[u8]) -> NodeHash {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+}
+
+// keccak-256 as used by the EVM, not the standardized SHA3-256
+#[cfg(feature = "keccak")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Keccak256Hasher;
+
+#[cfg(feature = "keccak")]
+impl Hasher for Keccak256Hasher {
+    fn digest(&self, data: &[u8]) -> NodeHash {
+        use sha3::{Digest, Keccak256};
+        Keccak256::digest(data).to_vec()
+    }
+}
+
+#[cfg(feature = "poseidon")]
+pub use poseidon::PoseidonHasher;
+
+// Poseidon over the BN254 scalar field with the circom parameters, so that proofs can be
+// checked inside of a circuit. Nodes are encoded as field elements instead of bytes:
+//
+//   leaf   = P(1, key[0..16], key[16..32], bytes(data), bytes(preimage))
+//   branch = P(2, split index, left, right)
+//   root   = P(3, left, right)
+//
+// where byte strings are read as big-endian integers, a missing child or byte string is 0,
+// bytes(b) = P(len(b)) folded with P(acc, chunk) over the 31 byte chunks of b, and every
+// hash is the 32 byte big-endian encoding of the resulting field element.
+#[cfg(feature = "poseidon")]
+mod poseidon {
+    use super::Hasher;
+    use crate::store::types::{Branch, Leaf, NodeHash, Root};
+    use ark_bn254::Fr;
+    use ark_ff::{BigInteger, PrimeField};
+    use light_poseidon::{Poseidon, PoseidonHasher as _};
+
+    const LEAF_TAG: u64 = 1;
+    const BRANCH_TAG: u64 = 2;
+    const ROOT_TAG: u64 = 3;
+    // chunks must stay below the modulus of the field
+    const CHUNK_LENGTH: usize = 31;
+
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PoseidonHasher;
+
+    impl PoseidonHasher {
+        fn poseidon(&self, inputs: &[Fr]) -> Fr {
+            // the number of inputs is fixed by the node encoding and always supported
+            Poseidon::<Fr>::new_circom(inputs.len())
+                .and_then(|mut poseidon| poseidon.hash(inputs))
+                .unwrap()
+        }
+        fn bytes(&self, bytes: Option<&[u8]>) -> Fr {
+            match bytes {
+                None => Fr::from(0u64),
+                Some(bytes) => bytes.chunks(CHUNK_LENGTH).fold(
+                    self.poseidon(&[Fr::from(bytes.len() as u64)]),
+                    |acc, chunk| self.poseidon(&[acc, Fr::from_be_bytes_mod_order(chunk)]),
+                ),
+            }
+        }
+        fn child(&self, child: &Option<NodeHash>) -> Fr {
+            match child {
+                Some(node_hash) => Fr::from_be_bytes_mod_order(node_hash),
+                None => Fr::from(0u64),
+            }
+        }
+    }
+
+    fn to_node_hash(element: Fr) -> NodeHash {
+        element.into_bigint().to_bytes_be()
+    }
+
+    impl Hasher for PoseidonHasher {
+        fn digest(&self, data: &[u8]) -> NodeHash {
+            to_node_hash(self.bytes(Some(data)))
+        }
+        fn hash_root(&self, root: &Root) -> NodeHash {
+            to_node_hash(self.poseidon(&[
+                Fr::from(ROOT_TAG),
+                self.child(&root.left),
+                self.child(&root.right),
+            ]))
+        }
+        fn hash_branch(&self, branch: &Branch) -> NodeHash {
+            to_node_hash(self.poseidon(&[
+                Fr::from(BRANCH_TAG),
+                Fr::from(branch.split_idx() as u64),
+                self.child(&branch.left),
+                self.child(&branch.right),
+            ]))
+        }
+        fn hash_leaf(&self, leaf: &Leaf) -> NodeHash {
+            let (key_high, key_low) = leaf.key.split_at(leaf.key.len() / 2);
+            to_node_hash(self.poseidon(&[
+                Fr::from(LEAF_TAG),
+                Fr::from_be_bytes_mod_order(key_high),
+                Fr::from_be_bytes_mod_order(key_low),
+                self.bytes(leaf.data.as_deref()),
+                self.bytes(leaf.preimage.as_deref()),
+            ]))
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use super::{
+    db::Database,
+    hasher::Hasher,
+    types::{Hashable, Key, Leaf, Node, NodeHash, Root, RootHash, KEY_BITS},
+};
+use crate::{
+    error::{Result, TrieError},
+    insert_batch, insert_batch_with, load_node,
+};
+
+// tries written before keys were packed store every bit of a key in its own byte
+pub fn pack_bits(bits: &[u8]) -> Result<Key> {
+    if bits.len() != KEY_BITS {
+        return Err(TrieError::InvalidKeyLength { len: bits.len() });
+    }
+    Ok(bits
+        .chunks(8)
+        .map(|chunk| chunk.iter().fold(0u8, |byte, bit| (byte << 1) | (bit & 1)))
+        .collect())
+}
+
+// copy every leaf below a root in the old 256 bit key format into a new trie with packed keys,
+// the old nodes are left untouched so both databases may point to the same sqlite file
+pub fn migrate_bit_keys(
+    old_db: &mut dyn Database,
+    old_root_hash: &RootHash,
+    new_db: &mut dyn Database,
+) -> Result<Root> {
+    let mut leaves: Vec<Leaf> = collect_leaves(old_db, old_root_hash)?;
+    for leaf in leaves.iter_mut() {
+        leaf.key = pack_bits(&leaf.key)?;
+        leaf.hash();
+    }
+    insert_batch(new_db, leaves, Node::Root(Root::empty()))
+}
+
+// rebuild the trie below a root with another hasher or after the node encoding changed,
+// every node is hashed again and the new root is returned
+pub fn rebuild_with(
+    old_db: &mut dyn Database,
+    old_root_hash: &RootHash,
+    new_db: &mut dyn Database,
+    hasher: &dyn Hasher,
+) -> Result<Root> {
+    let mut leaves: Vec<Leaf> = collect_leaves(old_db, old_root_hash)?;
+    for leaf in leaves.iter_mut() {
+        leaf.hash_with(hasher);
+    }
+    insert_batch_with(new_db, leaves, Node::Root(Root::empty()), hasher)
+}
+
+fn collect_leaves(db: &mut dyn Database, root_hash: &RootHash) -> Result<Vec<Leaf>> {
+    let root: Root = load_node(db, root_hash, 0)?.unwrap_as_root()?;
+    let mut pending: Vec<(NodeHash, usize)> = [root.left, root.right]
+        .into_iter()
+        .flatten()
+        .map(|node_hash| (node_hash, 1))
+        .collect();
+    let mut leaves: Vec<Leaf> = Vec::new();
+    while let Some((node_hash, depth)) = pending.pop() {
+        match load_node(db, &node_hash, depth)? {
+            Node::Branch(branch) => {
+                for child in [branch.left, branch.right].into_iter().flatten() {
+                    pending.push((child, depth + 1));
+                }
+            }
+            Node::Leaf(leaf) => leaves.push(leaf),
+            Node::Root(_) => return Err(TrieError::InvalidChild { depth }),
+        }
+    }
+    Ok(leaves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate_bit_keys, pack_bits};
+    use crate::store::db::memory::InMemoryDB;
+    use crate::store::db::Database;
+    use crate::store::types::{Branch, Hashable, Leaf, Node, Root};
+    use crate::{get_leaf, insert_batch};
+
+    // three leaves in the old format with one byte per key bit, and their packed keys
+    fn bit_key_leaves() -> Vec<(Leaf, [u8; 32])> {
+        let mut leaf_2_key: Vec<u8> = vec![0; 253];
+        leaf_2_key.extend([1, 1, 1]);
+        let mut leaf_2_packed_key = [0u8; 32];
+        leaf_2_packed_key[31] = 0b111;
+        [
+            (vec![0u8; 256], [0u8; 32]),
+            (leaf_2_key, leaf_2_packed_key),
+            (vec![1u8; 256], [u8::MAX; 32]),
+        ]
+        .into_iter()
+        .zip(1u8..)
+        .map(|((key, packed_key), data)| {
+            let mut leaf: Leaf = Leaf::new(key, Some(vec![data]));
+            leaf.hash();
+            (leaf, packed_key)
+        })
+        .collect()
+    }
+
+    // the migrated trie holds every leaf at its packed key and is the same trie as
+    // one that was built from the packed keys
+    fn check_migrated(new_db: &mut dyn Database, new_root: Root, leaves: &[(Leaf, [u8; 32])]) {
+        let mut packed_leafs: Vec<Leaf> = Vec::new();
+        for (leaf, packed_key) in leaves {
+            assert_eq!(pack_bits(&leaf.key).unwrap(), packed_key);
+            let stored_leaf = get_leaf(new_db, packed_key, Node::Root(new_root.clone()))
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored_leaf.data, leaf.data);
+            let mut packed_leaf: Leaf = Leaf::new(*packed_key, leaf.data.clone());
+            packed_leaf.hash();
+            packed_leafs.push(packed_leaf);
+        }
+        let expected_root = insert_batch(
+            &mut InMemoryDB::new(),
+            packed_leafs,
+            Node::Root(Root::empty()),
+        )
+        .unwrap();
+        assert_eq!(new_root.hash, expected_root.hash);
+    }
+
+    #[test]
+    fn test_migrate_bit_keys() {
+        let leaves = bit_key_leaves();
+        let mut old_db = InMemoryDB::new();
+        for (leaf, _) in &leaves {
+            leaf.store(&mut old_db).unwrap();
+        }
+        let mut branch = Branch::new(253, leaves[0].0.hash.clone(), leaves[1].0.hash.clone());
+        branch.hash_and_store(&mut old_db).unwrap();
+        let mut old_root = Root::empty();
+        old_root.left = branch.hash.clone();
+        old_root.right = leaves[2].0.hash.clone();
+        old_root.hash_and_store(&mut old_db).unwrap();
+
+        let mut new_db = InMemoryDB::new();
+        let new_root =
+            migrate_bit_keys(&mut old_db, old_root.hash.as_ref().unwrap(), &mut new_db).unwrap();
+        check_migrated(&mut new_db, new_root, &leaves);
+        assert!(pack_bits(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_migrate_bit_keys_from_sqlite() {
+        use crate::store::db::tests::TempDB;
+        use crate::store::types::{Key, NodeHash};
+        use rusqlite::{params, Connection};
+        use serde::Serialize;
+
+        // the nodes exactly as the first release wrote them, before the trie had a
+        // migration or leaves had a preimage
+        #[derive(Serialize)]
+        enum BaselineNode {
+            Root {
+                hash: Option<NodeHash>,
+                left: Option<NodeHash>,
+                right: Option<NodeHash>,
+            },
+            Branch {
+                key: Key,
+                hash: Option<NodeHash>,
+                left: Option<NodeHash>,
+                right: Option<NodeHash>,
+            },
+            Leaf {
+                prefix: Option<Key>,
+                key: Key,
+                hash: Option<NodeHash>,
+                data: Option<Vec<u8>>,
+            },
+        }
+        let leaves = bit_key_leaves();
+        let leaf_hashes: Vec<NodeHash> = (1u8..=3).map(|idx| vec![idx; 32]).collect();
+        let (branch_hash, root_hash): (NodeHash, NodeHash) = (vec![4; 32], vec![5; 32]);
+        let mut rows: Vec<(NodeHash, BaselineNode)> = leaves
+            .iter()
+            .zip(&leaf_hashes)
+            .map(|((leaf, _), hash)| {
+                let node = BaselineNode::Leaf {
+                    prefix: None,
+                    key: leaf.key.clone(),
+                    hash: Some(hash.clone()),
+                    data: leaf.data.clone(),
+                };
+                (hash.clone(), node)
+            })
+            .collect();
+        rows.push((
+            branch_hash.clone(),
+            BaselineNode::Branch {
+                key: vec![253],
+                hash: Some(branch_hash.clone()),
+                left: Some(leaf_hashes[0].clone()),
+                right: Some(leaf_hashes[1].clone()),
+            },
+        ));
+        rows.push((
+            root_hash.clone(),
+            BaselineNode::Root {
+                hash: Some(root_hash.clone()),
+                left: Some(branch_hash),
+                right: Some(leaf_hashes[2].clone()),
+            },
+        ));
+
+        // with the schema of the first release and none of the tables that came later
+        let temp_db = TempDB::new("bit-keys");
+        let conn = Connection::open(temp_db.path()).unwrap();
+        conn.execute(
+            "CREATE TABLE nodes (key BLOB PRIMARY KEY, node BLOB NOT NULL)",
+            [],
+        )
+        .unwrap();
+        for (key, node) in &rows {
+            conn.execute(
+                "INSERT INTO nodes (key, node) VALUES (?1, ?2)",
+                params![key, bincode::serialize(node).unwrap()],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        // both tries live in the same file afterwards
+        let mut old_db = temp_db.open();
+        let mut new_db = temp_db.open();
+        let new_root = migrate_bit_keys(&mut old_db, &root_hash, &mut new_db).unwrap();
+        check_migrated(&mut new_db, new_root, &leaves);
+        assert!(old_db.get(&root_hash).unwrap().is_some());
+    }
+}
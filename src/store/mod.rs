@@ -1,2 +1,6 @@
 pub mod db;
+pub mod encoding;
+pub mod hasher;
+pub mod migrate;
+pub mod prune;
 pub mod types;
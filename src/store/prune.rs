@@ -0,0 +1,269 @@
+use super::{
+    db::{Database, Prunable, RefCounted, RootRegistry},
+    types::{NodeHash, RootHash},
+};
+use crate::{error::Result, load_node};
+use std::collections::HashSet;
+
+// nodes are deleted in transactions of this size, an interrupted prune keeps
+// every batch that was committed before
+pub const PRUNE_BATCH_SIZE: usize = 1000;
+
+// delete every node that can't be reached from one of the kept roots and return how many
+// were deleted. Marking only reads, and only unreachable nodes are ever deleted, so a prune
+// that is interrupted leaves the kept tries intact and can simply be run again.
+// Nodes written while the prune runs are not marked, don't insert until it returned.
+pub fn prune<D: Database + Prunable>(db: &mut D, keep: &[RootHash]) -> Result<usize> {
+    let reachable: HashSet<NodeHash> = mark(db, keep)?;
+    let unreachable: Vec<NodeHash> = db
+        .node_hashes()?
+        .into_iter()
+        .filter(|node_hash| !reachable.contains(node_hash))
+        .collect();
+    for batch in unreachable.chunks(PRUNE_BATCH_SIZE) {
+        db.delete_batch(batch.to_vec())?;
+    }
+    Ok(unreachable.len())
+}
+
+// keep the roots of the last `versions` recorded heights together with the given roots,
+// older heights are removed from the registry before their nodes are deleted
+pub fn prune_versions<D: Database + Prunable + RootRegistry>(
+    db: &mut D,
+    versions: usize,
+    extra: &[RootHash],
+) -> Result<usize> {
+    let roots: Vec<(u64, RootHash)> = db.roots()?;
+    let kept: &[(u64, RootHash)] = &roots[roots.len().saturating_sub(versions)..];
+    match (kept.first(), roots.last()) {
+        (Some((height, _)), _) => db.remove_roots_below(*height)?,
+        // no version is kept, forget every height
+        (None, Some((height, _))) => db.remove_roots_below(height.saturating_add(1))?,
+        (None, None) => {}
+    }
+    let mut keep: Vec<RootHash> = kept
+        .iter()
+        .map(|(_, root_hash)| root_hash.clone())
+        .collect();
+    keep.extend_from_slice(extra);
+    prune(db, &keep)
+}
+
+// incremental counterpart of prune_versions for backends that count references, only the
+// nodes that no kept root links to anymore are visited. Roots are released before their
+// heights are forgotten, so an interrupted call can simply be repeated.
+pub fn release_versions<D: Database + RefCounted + RootRegistry>(
+    db: &mut D,
+    versions: usize,
+    extra: &[RootHash],
+) -> Result<usize> {
+    let roots: Vec<(u64, RootHash)> = db.roots()?;
+    let (released, kept) = roots.split_at(roots.len().saturating_sub(versions));
+    let mut deleted: usize = 0;
+    for (_, root_hash) in released {
+        // the same root may be recorded at a kept height
+        if kept.iter().any(|(_, kept_hash)| kept_hash == root_hash) || extra.contains(root_hash) {
+            continue;
+        }
+        deleted += db.release(root_hash)?;
+    }
+    if let Some((height, _)) = released.last() {
+        db.remove_roots_below(height.saturating_add(1))?;
+    }
+    Ok(deleted)
+}
+
+fn mark(db: &mut dyn Database, keep: &[RootHash]) -> Result<HashSet<NodeHash>> {
+    let mut reachable: HashSet<NodeHash> = HashSet::new();
+    let mut pending: Vec<(NodeHash, usize)> = keep
+        .iter()
+        .map(|root_hash| (root_hash.clone(), 0))
+        .collect();
+    while let Some((node_hash, depth)) = pending.pop() {
+        // subtrees are shared between roots and only walked once
+        if !reachable.insert(node_hash.clone()) {
+            continue;
+        }
+        for child in load_node(db, &node_hash, depth)?.children() {
+            pending.push((child, depth + 1));
+        }
+    }
+    Ok(reachable)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::TrieError;
+    use crate::insert_leaf;
+    use crate::merkle::tests::{generate_random_key, generate_random_leaves};
+    use crate::store::db::memory::InMemoryDB;
+    use crate::store::db::tests::TempDB;
+    use crate::store::db::{Database, RootRegistry};
+    use crate::store::types::{Key, Node, Root, RootHash};
+    use crate::trie::Trie;
+
+    // commits 20 keys in 5 versions of 4 keys and overwrites the first key in each of
+    // them, so that older roots hold leaves the head does not. The second key is then
+    // removed from the uncommitted head.
+    fn commit_versions<D: Database + RootRegistry>(
+        trie: &mut Trie<D>,
+    ) -> (Vec<Key>, Vec<RootHash>) {
+        let keys: Vec<Key> = (0..20).map(|_| generate_random_key()).collect();
+        let mut root_hashes = Vec::new();
+        for (height, chunk) in keys.chunks(4).enumerate() {
+            for key in chunk {
+                trie.insert(key.clone(), vec![height as u8]).unwrap();
+            }
+            trie.update(keys[0].clone(), vec![height as u8]).unwrap();
+            trie.commit(height as u64).unwrap();
+            root_hashes.push(trie.root_hash().clone());
+        }
+        trie.remove(&keys[1]).unwrap();
+        (keys, root_hashes)
+    }
+
+    #[test]
+    fn test_prune() {
+        use super::prune;
+        use crate::node_hash;
+        use crate::store::db::Prunable;
+
+        fn check_prune<D: Database + Prunable + RootRegistry>(db: D) -> D {
+            let mut trie = Trie::new(db).unwrap();
+            let (keys, root_hashes) = commit_versions(&mut trie);
+
+            assert!(trie.prune(2).unwrap() > 0);
+            assert_eq!(trie.prune(2).unwrap(), 0);
+            assert_eq!(
+                trie.db().roots().unwrap(),
+                vec![(3, root_hashes[3].clone()), (4, root_hashes[4].clone())]
+            );
+            for root_hash in &root_hashes[..3] {
+                assert!(matches!(
+                    trie.at(root_hash),
+                    Err(TrieError::MissingNode { depth: 0, .. })
+                ));
+            }
+            for root_hash in &root_hashes[3..] {
+                let mut view = trie.at(root_hash).unwrap();
+                for key in &keys[..16] {
+                    assert!(view.get(key).unwrap().is_some());
+                }
+            }
+            assert!(trie.get(&keys[1]).unwrap().is_none());
+            assert_eq!(trie.get(&keys[19]).unwrap().unwrap().data, Some(vec![4]));
+
+            // pruning down to the head leaves exactly the nodes of the head
+            let head_root_hash = trie.root_hash().clone();
+            let mut db = trie.into_db();
+            prune(&mut db, std::slice::from_ref(&head_root_hash)).unwrap();
+            let mut trie = Trie::open(db, &head_root_hash).unwrap();
+            for key in keys.iter().filter(|key| *key != &keys[1]) {
+                assert!(trie.get(key).unwrap().is_some());
+            }
+            // 19 leaves, one branch less than leaves on each side of the root, and the root
+            let root_children = [&trie.root().left, &trie.root().right]
+                .into_iter()
+                .flatten()
+                .count();
+            assert_eq!(
+                trie.db().node_hashes().unwrap().len(),
+                19 + (19 - root_children) + 1
+            );
+            trie.into_db()
+        }
+        check_prune(InMemoryDB::new());
+
+        // an interrupted prune that only deleted some of the unreachable nodes is resumed
+        let mut db = InMemoryDB::new();
+        let mut root = Node::Root(Root::empty());
+        let mut root_hashes = Vec::new();
+        for mut leaf in generate_random_leaves(10) {
+            root = Node::Root(insert_leaf(&mut db, &mut leaf, root).unwrap());
+            root_hashes.push(node_hash(&root).unwrap());
+        }
+        let keep = [root_hashes[9].clone()];
+        let mut pruned = db.clone();
+        prune(&mut pruned, &keep).unwrap();
+        let reachable = pruned.node_hashes().unwrap();
+        let unreachable: Vec<_> = db
+            .node_hashes()
+            .unwrap()
+            .into_iter()
+            .filter(|node_hash| !reachable.contains(node_hash))
+            .collect();
+        db.delete_batch(unreachable[..unreachable.len() / 2].to_vec())
+            .unwrap();
+        assert_eq!(
+            prune(&mut db, &keep).unwrap(),
+            unreachable.len() - unreachable.len() / 2
+        );
+        assert_eq!(db.len(), pruned.len());
+
+        drop(check_prune(TempDB::new("prune").open()));
+    }
+
+    #[test]
+    fn test_ref_counts() {
+        use super::prune;
+        use crate::store::db::{Prunable, RefCounted};
+
+        fn check_ref_counts<D: Database + Prunable + RefCounted + RootRegistry>(db: D) {
+            let mut trie = Trie::new(db).unwrap().with_auto_release();
+            let (keys, _) = commit_versions(&mut trie);
+            let root_hash = trie.root_hash().clone();
+            assert_eq!(trie.db().ref_count(&root_hash).unwrap(), Some(0));
+            let leaf_hash = trie.get(&keys[19]).unwrap().unwrap().hash.unwrap();
+            // every stored parent links the leaf once, the last committed version keeps its
+            // own parent if removing keys[1] from the head rewrote it
+            let parents = trie
+                .db()
+                .node_hashes()
+                .unwrap()
+                .into_iter()
+                .filter(|node_hash| {
+                    let node = trie.db().get(node_hash).unwrap().unwrap();
+                    node.children().contains(&leaf_hash)
+                })
+                .count();
+            assert!(parents > 0);
+            assert_eq!(
+                trie.db().ref_count(&leaf_hash).unwrap(),
+                Some(parents as u64)
+            );
+
+            // only the committed roots and the current root are left
+            let mut keep: Vec<_> = trie
+                .db()
+                .roots()
+                .unwrap()
+                .into_iter()
+                .map(|(_, root_hash)| root_hash)
+                .collect();
+            keep.push(root_hash.clone());
+            assert_eq!(prune(trie.db(), &keep).unwrap(), 0);
+
+            // releasing old versions deletes exactly what a full prune would
+            let first_root_hash = keep[0].clone();
+            assert!(trie.release_versions(2).unwrap() > 0);
+            assert_eq!(trie.release_versions(2).unwrap(), 0);
+            assert_eq!(trie.db().roots().unwrap().len(), 2);
+            assert!(trie.at(&first_root_hash).is_err());
+            let keep: Vec<_> = keep[3..].to_vec();
+            assert_eq!(prune(trie.db(), &keep).unwrap(), 0);
+            for root_hash in &keep[..2] {
+                let mut view = trie.at(root_hash).unwrap();
+                for key in &keys[..16] {
+                    assert!(view.get(key).unwrap().is_some());
+                }
+            }
+
+            // a root that is still linked or unknown is not released
+            assert_eq!(trie.db().release(&leaf_hash).unwrap(), 0);
+            assert_eq!(trie.db().release(&vec![7u8; 32]).unwrap(), 0);
+            assert_eq!(trie.get(&keys[19]).unwrap().unwrap().data, Some(vec![4]));
+        }
+        check_ref_counts(InMemoryDB::new());
+        check_ref_counts(TempDB::new("refs").open());
+    }
+}
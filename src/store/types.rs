@@ -1,5 +1,8 @@
-use super::db::Database;
-use anyhow::{bail, Result};
+use super::{
+    db::Database,
+    hasher::{Hasher, Sha256Hasher},
+};
+use crate::error::{Result, TrieError};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
@@ -8,6 +11,18 @@ pub type NodeHash = Vec<u8>;
 pub type Key = Vec<u8>;
 pub type Data = Vec<u8>;
 
+// keys are 32 bytes, every bit is one step on the path from the root
+pub const KEY_LENGTH: usize = 32;
+pub const KEY_BITS: usize = KEY_LENGTH * 8;
+
+// derives the key of a leaf from an application key of any length
+pub type KeyHasher = fn(&[u8]) -> Key;
+
+// the bit at a path index, starting with the most significant bit of the first byte
+pub fn key_bit(key: &[u8], idx: usize) -> u8 {
+    (key[idx / 8] >> (7 - idx % 8)) & 1
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Node {
     Root(Root),
@@ -19,21 +34,30 @@ impl Node {
     pub fn unwrap_as_root(self) -> Result<Root> {
         match self {
             Node::Root(root) => Ok(root),
-            _ => bail!("Failed to unwrap as Root"),
+            _ => Err(TrieError::UnexpectedNode { expected: "Root" }),
         }
     }
     pub fn unwrap_as_branch(self) -> Result<Branch> {
         match self {
             Node::Branch(branch) => Ok(branch),
-            _ => bail!("Failed to unwrap as Branch"),
+            _ => Err(TrieError::UnexpectedNode { expected: "Branch" }),
         }
     }
     pub fn unwrap_as_leaf(self) -> Result<Leaf> {
         match self {
             Node::Leaf(leaf) => Ok(leaf),
-            _ => bail!("Failed to unwrap as Leaf"),
+            _ => Err(TrieError::UnexpectedNode { expected: "Leaf" }),
         }
     }
+    // the hashes of the nodes that this node links to
+    pub fn children(&self) -> Vec<NodeHash> {
+        let (left, right) = match self {
+            Node::Root(root) => (&root.left, &root.right),
+            Node::Branch(branch) => (&branch.left, &branch.right),
+            Node::Leaf(_) => return Vec::new(),
+        };
+        [left, right].into_iter().flatten().cloned().collect()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -51,60 +75,110 @@ impl Root {
             right: None,
         }
     }
-    pub fn store(&self, db: &mut dyn Database) {
-        db.insert(
-            &self
-                .hash
-                .clone()
-                .expect("Must compute hash before storing a node, try calling .hash()"),
-            Node::Root(self.clone()),
-        )
+    pub fn store(&self, db: &mut dyn Database) -> Result<()> {
+        match &self.hash {
+            Some(hash) => db.insert(hash, Node::Root(self.clone())),
+            None => Err(TrieError::UnhashedNode),
+        }
+    }
+    pub fn hash_and_store(&mut self, db: &mut dyn Database) -> Result<()> {
+        self.hash_and_store_with(db, &Sha256Hasher)
     }
-    pub fn hash_and_store(&mut self, db: &mut dyn Database) {
-        self.hash = None;
-        self.hash();
-        self.store(db);
+    pub fn hash_and_store_with(
+        &mut self,
+        db: &mut dyn Database,
+        hasher: &dyn Hasher,
+    ) -> Result<()> {
+        self.hash_with(hasher);
+        self.store(db)
     }
 }
 
+// a branch only holds the index of the key bit that its children differ in. It is
+// stored as a key of exactly one byte, like before keys were packed.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "BranchFields", try_from = "BranchFields")]
 pub struct Branch {
-    pub key: Key,
+    split_idx: u8,
     pub hash: Option<NodeHash>,
     pub left: Option<NodeHash>,
     pub right: Option<NodeHash>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct BranchFields {
+    key: Key,
+    hash: Option<NodeHash>,
+    left: Option<NodeHash>,
+    right: Option<NodeHash>,
+}
+
+impl TryFrom<BranchFields> for Branch {
+    type Error = String;
+
+    fn try_from(fields: BranchFields) -> std::result::Result<Self, Self::Error> {
+        let [split_idx] = fields.key[..] else {
+            return Err(format!(
+                "branch key must be a single split index, found {} bytes",
+                fields.key.len()
+            ));
+        };
+        Ok(Self {
+            split_idx,
+            hash: fields.hash,
+            left: fields.left,
+            right: fields.right,
+        })
+    }
+}
+
+impl From<Branch> for BranchFields {
+    fn from(branch: Branch) -> Self {
+        Self {
+            key: vec![branch.split_idx],
+            hash: branch.hash,
+            left: branch.left,
+            right: branch.right,
+        }
+    }
+}
+
 impl Branch {
-    pub fn empty(key: Key) -> Self {
+    pub fn empty(split_idx: u8) -> Self {
         Self {
-            key,
+            split_idx,
             hash: None,
             left: None,
             right: None,
         }
     }
-    pub fn new(key: Key, left: Option<NodeHash>, right: Option<NodeHash>) -> Self {
+    pub fn new(split_idx: u8, left: Option<NodeHash>, right: Option<NodeHash>) -> Self {
         Self {
-            key,
+            split_idx,
             hash: None,
             left,
             right,
         }
     }
-    pub fn store(&self, db: &mut dyn Database) {
-        db.insert(
-            &self
-                .hash
-                .clone()
-                .expect("Must compute hash before storing a node, try calling .hash()"),
-            Node::Branch(self.clone()),
-        )
+    pub fn split_idx(&self) -> u8 {
+        self.split_idx
     }
-    pub fn hash_and_store(&mut self, db: &mut dyn Database) {
-        self.hash = None;
-        self.hash();
-        self.store(db);
+    pub fn store(&self, db: &mut dyn Database) -> Result<()> {
+        match &self.hash {
+            Some(hash) => db.insert(hash, Node::Branch(self.clone())),
+            None => Err(TrieError::UnhashedNode),
+        }
+    }
+    pub fn hash_and_store(&mut self, db: &mut dyn Database) -> Result<()> {
+        self.hash_and_store_with(db, &Sha256Hasher)
+    }
+    pub fn hash_and_store_with(
+        &mut self,
+        db: &mut dyn Database,
+        hasher: &dyn Hasher,
+    ) -> Result<()> {
+        self.hash_with(hasher);
+        self.store(db)
     }
     pub fn update(&mut self, left: Option<NodeHash>, right: Option<NodeHash>) {
         self.left = left;
@@ -114,67 +188,89 @@ impl Branch {
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Leaf {
-    pub prefix: Option<Key>,
+    // the application key that was hashed into the key of the leaf, if any
+    pub preimage: Option<Vec<u8>>,
     pub key: Key,
     pub hash: Option<NodeHash>,
     pub data: Option<Data>,
 }
 
 impl Leaf {
-    pub fn empty(key: Key) -> Self {
+    pub fn empty(key: impl Into<Key>) -> Self {
         Self {
-            prefix: None,
-            key,
+            preimage: None,
+            key: key.into(),
             hash: None,
             data: None,
         }
     }
-    pub fn new(key: Key, data: Option<Data>) -> Self {
+    pub fn new(key: impl Into<Key>, data: Option<Data>) -> Self {
+        Self {
+            preimage: None,
+            key: key.into(),
+            hash: None,
+            data,
+        }
+    }
+    // a leaf for an application key of any length, stored at the hash of the key
+    pub fn hashed(preimage: impl Into<Vec<u8>>, data: Option<Data>) -> Self {
+        Self::hashed_with(preimage, data, default_key_hasher)
+    }
+    pub fn hashed_with(
+        preimage: impl Into<Vec<u8>>,
+        data: Option<Data>,
+        key_hasher: KeyHasher,
+    ) -> Self {
+        let preimage: Vec<u8> = preimage.into();
         Self {
-            prefix: None,
-            key,
+            key: key_hasher(&preimage),
+            preimage: Some(preimage),
             hash: None,
             data,
         }
     }
-    pub fn hash_and_store(&mut self, db: &mut dyn Database) {
-        self.hash = None;
-        self.hash();
-        self.store(db);
+    pub fn hash_and_store(&mut self, db: &mut dyn Database) -> Result<()> {
+        self.hash_and_store_with(db, &Sha256Hasher)
     }
-    pub fn store(&self, db: &mut dyn Database) {
-        db.insert(
-            &self
-                .hash
-                .clone()
-                .expect("Must compute hash before storing a node, try calling .hash()"),
-            Node::Leaf(self.clone()),
-        )
+    pub fn hash_and_store_with(
+        &mut self,
+        db: &mut dyn Database,
+        hasher: &dyn Hasher,
+    ) -> Result<()> {
+        self.hash_with(hasher);
+        self.store(db)
+    }
+    pub fn store(&self, db: &mut dyn Database) -> Result<()> {
+        match &self.hash {
+            Some(hash) => db.insert(hash, Node::Leaf(self.clone())),
+            None => Err(TrieError::UnhashedNode),
+        }
     }
 }
 
 pub trait Hashable {
-    fn hash(&mut self);
+    // hash with the given hasher, the hash field itself is never part of the hash
+    fn hash_with(&mut self, hasher: &dyn Hasher);
+    fn hash(&mut self) {
+        self.hash_with(&Sha256Hasher)
+    }
 }
 
 impl Hashable for Root {
-    fn hash(&mut self) {
-        self.hash = None;
-        self.hash = Some(default_hash(bincode::serialize(&self).unwrap()));
+    fn hash_with(&mut self, hasher: &dyn Hasher) {
+        self.hash = Some(hasher.hash_root(self));
     }
 }
 
 impl Hashable for Branch {
-    fn hash(&mut self) {
-        self.hash = None;
-        self.hash = Some(default_hash(bincode::serialize(&self).unwrap()));
+    fn hash_with(&mut self, hasher: &dyn Hasher) {
+        self.hash = Some(hasher.hash_branch(self));
     }
 }
 
 impl Hashable for Leaf {
-    fn hash(&mut self) {
-        self.hash = None;
-        self.hash = Some(default_hash(bincode::serialize(&self).unwrap()));
+    fn hash_with(&mut self, hasher: &dyn Hasher) {
+        self.hash = Some(hasher.hash_leaf(self));
     }
 }
 
@@ -183,3 +279,7 @@ pub fn default_hash<T: AsRef<[u8]>>(data: T) -> NodeHash {
     hasher.update(data);
     hasher.finalize().to_vec()
 }
+
+pub fn default_key_hasher(preimage: &[u8]) -> Key {
+    default_hash(preimage)
+}
@@ -0,0 +1,461 @@
+use crate::{
+    error::Result,
+    get_leaf, insert_leaf_with,
+    iter::{iter, iter_prefix, prefix_hash, range, TrieIter},
+    load_node,
+    merkle::{merkle_proof, range_proof, MerkleProof, RangeProof},
+    remove_leaf_with,
+    store::{
+        db::{Database, Prunable, RefCounted, RootRegistry},
+        hasher::{Hasher, Sha256Hasher},
+        prune::{prune_versions, release_versions},
+        types::{
+            default_key_hasher, Data, Hashable, Key, KeyHasher, Leaf, Node, NodeHash, Root,
+            RootHash,
+        },
+    },
+    update_leaf_with, upsert_leaf_with, UpsertKind,
+};
+
+// owns a database backend and the root of the latest state,
+// every mutation moves the trie to the new root
+pub struct Trie<D: Database, H: Hasher = Sha256Hasher> {
+    db: D,
+    root: Root,
+    hasher: H,
+    key_hasher: KeyHasher,
+    release: Option<ReleaseFn<D>>,
+}
+
+type ReleaseFn<D> = fn(&mut D, &RootHash) -> Result<usize>;
+
+impl<D: Database> Trie<D> {
+    // start with an empty trie, the empty root is stored so that it can be opened by hash
+    pub fn new(db: D) -> Result<Self> {
+        Self::with_hasher(db, Sha256Hasher)
+    }
+    // continue from a root that is already in the database
+    pub fn open(db: D, root_hash: &RootHash) -> Result<Self> {
+        Self::open_with_hasher(db, root_hash, Sha256Hasher)
+    }
+}
+
+impl<D: Database + RootRegistry> Trie<D> {
+    // continue from the root that was recorded last, or start empty if there is none
+    pub fn open_latest(db: D) -> Result<Self> {
+        Self::open_latest_with_hasher(db, Sha256Hasher)
+    }
+}
+
+impl<D: Database + RootRegistry, H: Hasher> Trie<D, H> {
+    pub fn open_latest_with_hasher(mut db: D, hasher: H) -> Result<Self> {
+        match db.latest()? {
+            Some((_, root_hash)) => Self::open_with_hasher(db, &root_hash, hasher),
+            None => Self::with_hasher(db, hasher),
+        }
+    }
+    // record the current root under a version or block height
+    pub fn commit(&mut self, height: u64) -> Result<()> {
+        let root_hash = self.root_hash().clone();
+        self.db.record_root(height, &root_hash)
+    }
+}
+
+impl<D: Database + Prunable + RootRegistry, H: Hasher> Trie<D, H> {
+    // delete the nodes of all but the last `versions` recorded roots, the current root
+    // is kept even if it was not committed
+    pub fn prune(&mut self, versions: usize) -> Result<usize> {
+        let root_hash = self.root_hash().clone();
+        prune_versions(&mut self.db, versions, &[root_hash])
+    }
+}
+
+impl<D: Database + RefCounted + RootRegistry, H: Hasher> Trie<D, H> {
+    // release the previous root on every mutation unless it was committed, so that only
+    // the nodes of committed roots and the current root stay in the database
+    pub fn with_auto_release(mut self) -> Self {
+        self.release = Some(|db, root_hash| {
+            if db.is_recorded(root_hash)? {
+                return Ok(0);
+            }
+            db.release(root_hash)
+        });
+        self
+    }
+    // incremental counterpart of prune, only the nodes of the forgotten roots are visited
+    pub fn release_versions(&mut self, versions: usize) -> Result<usize> {
+        let root_hash = self.root_hash().clone();
+        release_versions(&mut self.db, versions, &[root_hash])
+    }
+}
+
+impl<D: Database, H: Hasher> Trie<D, H> {
+    // every node of the trie is hashed with the given hasher
+    pub fn with_hasher(mut db: D, hasher: H) -> Result<Self> {
+        let mut root = Root::empty();
+        root.hash_and_store_with(&mut db, &hasher)?;
+        Ok(Self {
+            db,
+            root,
+            hasher,
+            key_hasher: default_key_hasher,
+            release: None,
+        })
+    }
+    pub fn open_with_hasher(mut db: D, root_hash: &RootHash, hasher: H) -> Result<Self> {
+        let root = load_root(&mut db, root_hash)?;
+        Ok(Self {
+            db,
+            root,
+            hasher,
+            key_hasher: default_key_hasher,
+            release: None,
+        })
+    }
+    // the hasher that derives keys for the *_hashed methods, default_key_hasher unless set
+    pub fn with_key_hasher(mut self, key_hasher: KeyHasher) -> Self {
+        self.key_hasher = key_hasher;
+        self
+    }
+    pub fn root(&self) -> &Root {
+        &self.root
+    }
+    pub fn root_hash(&self) -> &RootHash {
+        // the root is hashed on every path that sets it
+        self.root.hash.as_ref().unwrap()
+    }
+    // proofs of this trie must be verified with the same hasher
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+    pub fn db(&mut self) -> &mut D {
+        &mut self.db
+    }
+    pub fn into_db(self) -> D {
+        self.db
+    }
+    pub fn insert(&mut self, key: impl Into<Key>, data: Data) -> Result<()> {
+        let leaf = Leaf::new(key, Some(data));
+        self.insert_leaf(leaf)
+    }
+    // replaces the whole leaf, use update_hashed for keys that were inserted with insert_hashed
+    pub fn update(&mut self, key: impl Into<Key>, data: Data) -> Result<()> {
+        let leaf = Leaf::new(key, Some(data));
+        self.update_leaf(leaf)
+    }
+    pub fn upsert(&mut self, key: impl Into<Key>, data: Data) -> Result<UpsertKind> {
+        let leaf = Leaf::new(key, Some(data));
+        self.upsert_leaf(leaf)
+    }
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        let root_node = Node::Root(self.root.clone());
+        let root = remove_leaf_with(&mut self.db, key, root_node, &self.hasher)?;
+        self.move_to(root)
+    }
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Leaf>> {
+        get_leaf(&mut self.db, key, Node::Root(self.root.clone()))
+    }
+    pub fn prove(&mut self, key: &[u8]) -> Result<MerkleProof> {
+        merkle_proof(&mut self.db, key, Node::Root(self.root.clone()))
+    }
+    // all leaves in ascending key order
+    pub fn iter(&mut self) -> Result<TrieIter<'_>> {
+        iter(&mut self.db, Node::Root(self.root.clone()))
+    }
+    // the leaves with a key in [start, end)
+    pub fn range(&mut self, start: &[u8], end: &[u8]) -> Result<Vec<Leaf>> {
+        range(&mut self.db, start, end, Node::Root(self.root.clone()))
+    }
+    // the leaves whose keys start with the first prefix_bits bits of prefix
+    pub fn iter_prefix(&mut self, prefix: &[u8], prefix_bits: usize) -> Result<TrieIter<'_>> {
+        let root_node = Node::Root(self.root.clone());
+        iter_prefix(&mut self.db, prefix, prefix_bits, root_node)
+    }
+    pub fn prefix_hash(&mut self, prefix: &[u8], prefix_bits: usize) -> Result<Option<NodeHash>> {
+        let root_node = Node::Root(self.root.clone());
+        prefix_hash(&mut self.db, prefix, prefix_bits, root_node)
+    }
+    pub fn prove_range(&mut self, start: &[u8], end: &[u8]) -> Result<RangeProof> {
+        range_proof(&mut self.db, start, end, Node::Root(self.root.clone()))
+    }
+    // insert under the hash of an application key of any length, the key
+    // itself is stored as the preimage of the leaf
+    pub fn insert_hashed(&mut self, preimage: impl Into<Vec<u8>>, data: Data) -> Result<()> {
+        let leaf = Leaf::hashed_with(preimage, Some(data), self.key_hasher);
+        self.insert_leaf(leaf)
+    }
+    // the preimage is stored again with the new data
+    pub fn update_hashed(&mut self, preimage: impl Into<Vec<u8>>, data: Data) -> Result<()> {
+        let leaf = Leaf::hashed_with(preimage, Some(data), self.key_hasher);
+        self.update_leaf(leaf)
+    }
+    pub fn upsert_hashed(
+        &mut self,
+        preimage: impl Into<Vec<u8>>,
+        data: Data,
+    ) -> Result<UpsertKind> {
+        let leaf = Leaf::hashed_with(preimage, Some(data), self.key_hasher);
+        self.upsert_leaf(leaf)
+    }
+    pub fn get_hashed(&mut self, preimage: &[u8]) -> Result<Option<Leaf>> {
+        let key = (self.key_hasher)(preimage);
+        self.get(&key)
+    }
+    pub fn remove_hashed(&mut self, preimage: &[u8]) -> Result<()> {
+        let key = (self.key_hasher)(preimage);
+        self.remove(&key)
+    }
+    pub fn prove_hashed(&mut self, preimage: &[u8]) -> Result<MerkleProof> {
+        let key = (self.key_hasher)(preimage);
+        self.prove(&key)
+    }
+    fn insert_leaf(&mut self, mut leaf: Leaf) -> Result<()> {
+        leaf.hash_with(&self.hasher);
+        let root_node = Node::Root(self.root.clone());
+        let root = insert_leaf_with(&mut self.db, &mut leaf, root_node, &self.hasher)?;
+        self.move_to(root)
+    }
+    fn update_leaf(&mut self, mut leaf: Leaf) -> Result<()> {
+        leaf.hash_with(&self.hasher);
+        let root_node = Node::Root(self.root.clone());
+        let root = update_leaf_with(&mut self.db, &mut leaf, root_node, &self.hasher)?;
+        self.move_to(root)
+    }
+    fn upsert_leaf(&mut self, mut leaf: Leaf) -> Result<UpsertKind> {
+        leaf.hash_with(&self.hasher);
+        let root_node = Node::Root(self.root.clone());
+        let (root, kind) = upsert_leaf_with(&mut self.db, &mut leaf, root_node, &self.hasher)?;
+        self.move_to(root)?;
+        Ok(kind)
+    }
+    fn move_to(&mut self, root: Root) -> Result<()> {
+        let previous = std::mem::replace(&mut self.root, root);
+        if let (Some(release), Some(previous_hash)) = (self.release, previous.hash) {
+            if &previous_hash != self.root_hash() {
+                release(&mut self.db, &previous_hash)?;
+            }
+        }
+        Ok(())
+    }
+    // read only view of the trie at an earlier root
+    pub fn at(&mut self, root_hash: &RootHash) -> Result<TrieView<'_, D>> {
+        let root = load_root(&mut self.db, root_hash)?;
+        Ok(TrieView {
+            db: &mut self.db,
+            root,
+            key_hasher: self.key_hasher,
+        })
+    }
+}
+
+pub struct TrieView<'a, D: Database> {
+    db: &'a mut D,
+    root: Root,
+    key_hasher: KeyHasher,
+}
+
+impl<D: Database> TrieView<'_, D> {
+    pub fn root(&self) -> &Root {
+        &self.root
+    }
+    pub fn root_hash(&self) -> &RootHash {
+        self.root.hash.as_ref().unwrap()
+    }
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Leaf>> {
+        get_leaf(self.db, key, Node::Root(self.root.clone()))
+    }
+    pub fn prove(&mut self, key: &[u8]) -> Result<MerkleProof> {
+        merkle_proof(self.db, key, Node::Root(self.root.clone()))
+    }
+    pub fn iter(&mut self) -> Result<TrieIter<'_>> {
+        iter(self.db, Node::Root(self.root.clone()))
+    }
+    pub fn range(&mut self, start: &[u8], end: &[u8]) -> Result<Vec<Leaf>> {
+        range(self.db, start, end, Node::Root(self.root.clone()))
+    }
+    pub fn iter_prefix(&mut self, prefix: &[u8], prefix_bits: usize) -> Result<TrieIter<'_>> {
+        iter_prefix(self.db, prefix, prefix_bits, Node::Root(self.root.clone()))
+    }
+    pub fn prefix_hash(&mut self, prefix: &[u8], prefix_bits: usize) -> Result<Option<NodeHash>> {
+        prefix_hash(self.db, prefix, prefix_bits, Node::Root(self.root.clone()))
+    }
+    pub fn prove_range(&mut self, start: &[u8], end: &[u8]) -> Result<RangeProof> {
+        range_proof(self.db, start, end, Node::Root(self.root.clone()))
+    }
+    pub fn get_hashed(&mut self, preimage: &[u8]) -> Result<Option<Leaf>> {
+        let key = (self.key_hasher)(preimage);
+        self.get(&key)
+    }
+    pub fn prove_hashed(&mut self, preimage: &[u8]) -> Result<MerkleProof> {
+        let key = (self.key_hasher)(preimage);
+        self.prove(&key)
+    }
+}
+
+fn load_root(db: &mut dyn Database, root_hash: &RootHash) -> Result<Root> {
+    load_node(db, root_hash, 0)?.unwrap_as_root()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Trie;
+    use crate::error::TrieError;
+    use crate::merkle::tests::generate_random_key;
+    use crate::store::db::memory::InMemoryDB;
+    use crate::store::db::tests::TempDB;
+    use crate::store::db::{Database, RootRegistry};
+    use crate::store::types::{Hashable, Leaf, Node, Root};
+    use crate::{get_leaf, insert_leaf, UpsertKind};
+
+    #[test]
+    fn test_trie_handle() {
+        use crate::merkle::verify_merkle_proof;
+
+        let mut trie = Trie::new(InMemoryDB::new()).unwrap();
+        let empty_root_hash = trie.root_hash().clone();
+        let key_1 = generate_random_key();
+        let key_2 = generate_random_key();
+        trie.insert(key_1.clone(), vec![1]).unwrap();
+        let first_root_hash = trie.root_hash().clone();
+        trie.insert(key_2.clone(), vec![2]).unwrap();
+        assert!(trie.insert(key_2.clone(), vec![3]).is_err());
+        assert_eq!(
+            trie.upsert(key_2.clone(), vec![3]).unwrap(),
+            UpsertKind::Updated
+        );
+        assert_eq!(trie.get(&key_2).unwrap().unwrap().data, Some(vec![3]));
+
+        let proof = trie.prove(&key_1).unwrap();
+        assert!(verify_merkle_proof(proof.nodes, trie.root_hash().clone()).unwrap());
+
+        trie.remove(&key_1).unwrap();
+        assert!(trie.get(&key_1).unwrap().is_none());
+
+        // earlier roots stay readable
+        let mut view = trie.at(&first_root_hash).unwrap();
+        assert_eq!(view.get(&key_1).unwrap().unwrap().data, Some(vec![1]));
+        assert!(view.get(&key_2).unwrap().is_none());
+        let proof = view.prove(&key_1).unwrap();
+        assert!(verify_merkle_proof(proof.nodes, first_root_hash.clone()).unwrap());
+        assert!(trie
+            .at(&empty_root_hash)
+            .unwrap()
+            .get(&key_2)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            trie.at(&vec![7u8; 32]),
+            Err(TrieError::MissingNode { depth: 0, .. })
+        ));
+
+        // a trie can be reopened from its root hash
+        let root_hash = trie.root_hash().clone();
+        let mut reopened = Trie::open(trie.into_db(), &root_hash).unwrap();
+        assert_eq!(reopened.get(&key_2).unwrap().unwrap().data, Some(vec![3]));
+    }
+
+    #[test]
+    fn test_root_registry() {
+        fn check_registry<D: Database + RootRegistry>(db: D) -> D {
+            let mut trie = Trie::open_latest(db).unwrap();
+            assert!(trie.db().latest().unwrap().is_none());
+            let empty_root_hash = trie.root_hash().clone();
+            let key = generate_random_key();
+            trie.commit(0).unwrap();
+            trie.insert(key.clone(), vec![1]).unwrap();
+            trie.commit(1).unwrap();
+            let first_root_hash = trie.root_hash().clone();
+            trie.update(key.clone(), vec![2]).unwrap();
+            // a reorg replaces the root at a height
+            trie.commit(2).unwrap();
+            trie.update(key.clone(), vec![3]).unwrap();
+            trie.commit(2).unwrap();
+            let head_root_hash = trie.root_hash().clone();
+
+            let mut db = trie.into_db();
+            assert_eq!(db.root_at(1).unwrap(), Some(first_root_hash.clone()));
+            assert!(db.root_at(3).unwrap().is_none());
+            assert_eq!(db.latest().unwrap(), Some((2, head_root_hash.clone())));
+            assert_eq!(
+                db.roots().unwrap(),
+                vec![
+                    (0, empty_root_hash),
+                    (1, first_root_hash),
+                    (2, head_root_hash.clone())
+                ]
+            );
+
+            // the head is found again without knowing its hash
+            let mut trie = Trie::open_latest(db).unwrap();
+            assert_eq!(trie.root_hash(), &head_root_hash);
+            assert_eq!(trie.get(&key).unwrap().unwrap().data, Some(vec![3]));
+            trie.into_db()
+        }
+        check_registry(InMemoryDB::new());
+
+        let temp_db = TempDB::new("roots");
+        check_registry(temp_db.open());
+        // the registry survives a restart
+        assert_eq!(temp_db.open().roots().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_hashed_keys() {
+        use crate::merkle::verify_merkle_proof;
+        use crate::store::types::{default_hash, default_key_hasher, Key};
+
+        let mut db = InMemoryDB::new();
+        let mut leaf: Leaf = Leaf::hashed("alice", Some(vec![1]));
+        leaf.hash();
+        assert_eq!(leaf.key, default_key_hasher(b"alice"));
+        let root = insert_leaf(&mut db, &mut leaf, Node::Root(Root::empty())).unwrap();
+        let stored_leaf = get_leaf(&mut db, &default_key_hasher(b"alice"), Node::Root(root))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_leaf.preimage, Some(b"alice".to_vec()));
+
+        let mut trie = Trie::new(InMemoryDB::new()).unwrap();
+        let long_key = vec![7u8; 1000];
+        trie.insert_hashed("alice", vec![1]).unwrap();
+        trie.insert_hashed(long_key.clone(), vec![2]).unwrap();
+        assert!(trie.insert_hashed("alice", vec![3]).is_err());
+        let stored_leaf = trie.get_hashed(&long_key).unwrap().unwrap();
+        assert_eq!(stored_leaf.preimage, Some(long_key.clone()));
+        assert_eq!(stored_leaf.data, Some(vec![2]));
+        assert!(trie.get_hashed(b"bob").unwrap().is_none());
+        let proof = trie.prove_hashed(b"alice").unwrap();
+        assert!(verify_merkle_proof(proof.nodes, trie.root_hash().clone()).unwrap());
+
+        // the preimage survives an update
+        trie.update_hashed(long_key.clone(), vec![3]).unwrap();
+        let stored_leaf = trie.get_hashed(&long_key).unwrap().unwrap();
+        assert_eq!(stored_leaf.preimage, Some(long_key.clone()));
+        assert_eq!(stored_leaf.data, Some(vec![3]));
+        assert_eq!(
+            trie.upsert_hashed(long_key.clone(), vec![4]).unwrap(),
+            UpsertKind::Updated
+        );
+        assert_eq!(
+            trie.upsert_hashed("bob", vec![5]).unwrap(),
+            UpsertKind::Inserted
+        );
+        for (preimage, data) in [(long_key.clone(), vec![4]), (b"bob".to_vec(), vec![5])] {
+            let stored_leaf = trie.get_hashed(&preimage).unwrap().unwrap();
+            assert_eq!(stored_leaf.preimage, Some(preimage));
+            assert_eq!(stored_leaf.data, Some(data));
+        }
+        trie.remove_hashed(b"alice").unwrap();
+        assert!(trie.get_hashed(b"alice").unwrap().is_none());
+
+        // keys can be derived with any hasher that returns 32 bytes
+        fn namespaced_hasher(preimage: &[u8]) -> Key {
+            default_hash([b"accounts/".as_slice(), preimage].concat())
+        }
+        let mut trie = Trie::new(InMemoryDB::new())
+            .unwrap()
+            .with_key_hasher(namespaced_hasher);
+        trie.insert_hashed("alice", vec![1]).unwrap();
+        assert!(trie.get(&default_key_hasher(b"alice")).unwrap().is_none());
+        assert!(trie.get(&namespaced_hasher(b"alice")).unwrap().is_some());
+    }
+}